@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE regions
+ADD COLUMN schedule jsonb DEFAULT '[]'::jsonb NOT NULL;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE status
+DROP COLUMN clock_updated_at;
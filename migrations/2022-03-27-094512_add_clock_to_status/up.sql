@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE status
+ADD COLUMN clock_updated_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc');
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+DROP COLUMN last_attempt;
+
+ALTER TABLE status
+DROP COLUMN final_score;
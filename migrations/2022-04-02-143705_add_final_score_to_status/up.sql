@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE status
+ADD COLUMN final_score INT;
+
+ALTER TABLE users
+ADD COLUMN last_attempt INT REFERENCES status(id);
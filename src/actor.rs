@@ -1,6 +1,8 @@
-mod controllers;
+pub mod controllers;
+pub mod encoding;
 pub mod events;
 mod implementation;
+pub use implementation::UserLocks;
 pub mod routes;
 // mod tests;
 mod utils;
@@ -1,35 +1,331 @@
 use crate::actor::events::types::{
-    ActionResponse, ControlMeasure, ControlMeasureAction, ControlMeasureParams, Event, EventAction,
-    EventParams, Read, Save, Seed, SimulatorParams, SimulatorResponse, Start, StartParams,
-    WSResponse,
+    ActionResponse, CasesResponse, ControlMeasure, ControlMeasureAction, ControlMeasureParams,
+    ErrorCode, Event, EventAction, EventParams, Forecast, ForecastResponse, MetricsResponse, Read,
+    Save, Seed, ServerMessage, SimulatorResponse, Start, TriggerResponse,
 };
 use crate::db::models;
 use diesel::prelude::*;
 use diesel::PgConnection;
 
-use crate::actor::utils::{serialize_state, simulate, zip};
+use crate::actor::encoding::{Payload, PayloadEncoding};
+use crate::actor::utils::{
+    config, forecast, simulate, simulate_coupled, simulate_watching, with_crossings, LevelRegions,
+    TOTAL_DAYS,
+};
 use crate::auth::extractors;
 
 use crate::db::types::DbError;
+use chrono::{NaiveDateTime, Utc};
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
-use virus_simulator::Simulator;
 
 use tracing::{error, info, instrument};
+use virus_simulator::analysis::Summary;
+use virus_simulator::detection;
+use virus_simulator::ensemble::{Distribution, Ensemble, Perturbation, SampledParameter};
+use virus_simulator::level::{self, adjustable_params, apply_delta, scheduled_parameters};
+use virus_simulator::stochastic::TauLeaping;
+use virus_simulator::{Schedule, SimulatorConfig, State};
 
 const POPULATION: f64 = 5000.0;
-const TOTAL_DAYS: f64 = 700.0;
 const EVENT_POSTPONE_PENALTY: i32 = 100;
+/// Fraction of a region infectious above which a day counts towards `days_above_threshold`
+pub const INFECTIOUS_THRESHOLD: f64 = 0.01;
+
+/// Fastest pace a level can be played at. The server's date never gets ahead of the time
+/// elapsed since it last moved at this pace.
+const MAX_DAYS_PER_SECOND: f64 = 4.0;
+
+const FORECAST_RUNS: usize = 100;
+/// How unsure forecasts are of how the region will behave from now on
+const FORECAST_PERTURBATIONS: &[Perturbation] = &[
+    Perturbation {
+        parameter: SampledParameter::IdealReproductionNumber,
+        factor: Distribution::LogNormal { sigma: 0.15 },
+    },
+    Perturbation {
+        parameter: SampledParameter::ComplianceFactor,
+        factor: Distribution::LogNormal { sigma: 0.2 },
+    },
+    Perturbation {
+        parameter: SampledParameter::InfectionRate,
+        factor: Distribution::LogNormal { sigma: 0.1 },
+    },
+];
+
+/// News of a control measure or event of the level, `None` if the level has none for `key`
+pub fn get_description(key: &str, level: i32) -> Result<Option<Read>, DbError> {
+    let file = format!("src/game/levels/{}/description.json", level);
+    let contents = fs::read_to_string(Path::new(&file))?;
+    let mut obj = serde_json::from_str::<HashMap<String, Read>>(&contents)?;
+    Ok(obj.remove(key))
+}
 
-const PARAM_LIMITS: &[(f64, f64)] = &[(1.2, 3.0), (0.0, 0.8), (0.05, 0.1), (0.05, 0.30)];
+/// Reads the starting params of a region from the level's start file. Rates that are a
+/// property of the level, like the fatality rate, are taken from here and never from the client.
+pub fn get_start_params(level: i32, region: i32) -> Result<SimulatorConfig, DbError> {
+    match get_start_data(level)?.region(&region.to_string()) {
+        Some(start_params) => Ok(start_params),
+        None => Err(format!("Region {} not found in level {}", region, level).into()),
+    }
+}
 
-pub fn get_description(key: String, level: i32) -> Read {
-    let file = format!("src/game/levels/{}/description.json", level);
-    let path = Path::new(&file);
-    let contents = fs::read_to_string(&path).expect("Something went wrong reading the file");
-    let obj = serde_json::from_str::<HashMap<String, Read>>(&contents).unwrap();
-    obj.get(&key).expect("Invalid key").clone()
+pub fn get_start_data(level: i32) -> Result<level::Start, DbError> {
+    let file = format!("src/game/levels/{}/start.json", level);
+    let contents = fs::read_to_string(Path::new(&file))?;
+    Ok(serde_json::from_str::<level::Start>(&contents)?)
+}
+
+pub fn get_control_measure_data(
+    level: i32,
+) -> Result<HashMap<String, ControlMeasureParams>, DbError> {
+    let file = format!("src/game/levels/{}/control.json", level);
+    let contents = fs::read_to_string(Path::new(&file))?;
+    Ok(serde_json::from_str::<HashMap<String, ControlMeasureParams>>(&contents)?)
+}
+
+pub fn get_event_data(level: i32) -> Result<HashMap<String, EventParams>, DbError> {
+    let file = format!("src/game/levels/{}/event.json", level);
+    let contents = fs::read_to_string(Path::new(&file))?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+pub fn get_active_control_measures(
+    conn: &PgConnection,
+    status_id: i32,
+    region: i32,
+) -> Result<HashMap<String, i32>, DbError> {
+    use crate::db::schema::{regions, regions_status};
+    let active_control_measures = (regions::table)
+        .inner_join(regions_status::table)
+        .filter(regions_status::status_id.eq(status_id))
+        .filter(regions::region_id.eq(region))
+        .select(regions::active_control_measures)
+        .first::<models::status::ActiveControlMeasures>(conn)
+        .optional()?;
+    Ok(active_control_measures.map_or_else(HashMap::new, |x| x.0))
+}
+
+pub fn get_schedule(conn: &PgConnection, status_id: i32, region: i32) -> Result<Schedule, DbError> {
+    use crate::db::schema::{regions, regions_status};
+    let schedule = (regions::table)
+        .inner_join(regions_status::table)
+        .filter(regions_status::status_id.eq(status_id))
+        .filter(regions::region_id.eq(region))
+        .select(regions::schedule)
+        .first::<models::status::ParameterSchedule>(conn)
+        .optional()?;
+    Ok(schedule.map_or_else(Schedule::new, |x| x.0))
+}
+
+/// Moves the date of a game towards `requested`, the day the client says it is on, as far as the
+/// server's clock allows, and returns the date actions take effect on. The date never goes back,
+/// never moves faster than [`MAX_DAYS_PER_SECOND`] and stops at the end of the level, so that
+/// players can't act on days they have already seen the outcome of.
+pub fn advance_clock(conn: &PgConnection, status_id: i32, requested: i32) -> Result<i32, DbError> {
+    use crate::db::schema::status;
+    let (date, updated_at) = status::table
+        .filter(status::id.eq(status_id))
+        .select((status::cur_date, status::clock_updated_at))
+        .first::<(i32, NaiveDateTime)>(conn)?;
+    let now = Utc::now().naive_utc();
+    let elapsed = (now - updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+    let latest = (date as f64 + elapsed * MAX_DAYS_PER_SECOND).min(TOTAL_DAYS) as i32;
+    let new_date = requested.min(latest).max(date);
+    if new_date != date {
+        diesel::update(status::table.filter(status::id.eq(status_id)))
+            .set((
+                status::cur_date.eq(new_date),
+                status::clock_updated_at.eq(now),
+            ))
+            .execute(conn)?;
+    }
+    Ok(new_date)
+}
+
+fn level_regions(
+    conn: &PgConnection,
+    level: i32,
+    status_id: i32,
+    region: i32,
+    schedule: &Schedule,
+) -> Result<LevelRegions, DbError> {
+    use crate::db::schema::{regions, regions_status};
+
+    let mut start_data = get_start_data(level)?;
+    let mobility = match start_data.mobility.take() {
+        Some(mobility) => mobility,
+        None => {
+            let params = start_data
+                .region(&region.to_string())
+                .ok_or(format!("Region {} not found in level {}", region, level))?;
+            return Ok(LevelRegions {
+                regions: vec![config(params, schedule)],
+                mobility: None,
+                index: 0,
+                solver: start_data.solver,
+                triggers: start_data.triggers,
+            });
+        }
+    };
+    if region < 1 || region as usize > mobility.len() {
+        return Err(format!("Region {} not found in level {}", region, level).into());
+    }
+
+    let saved_schedules = (regions::table)
+        .inner_join(regions_status::table)
+        .filter(regions_status::status_id.eq(status_id))
+        .select((regions::region_id, regions::schedule))
+        .load::<(i32, models::status::ParameterSchedule)>(conn)?;
+
+    let mut regions = Vec::with_capacity(mobility.len());
+    for other in 1..=mobility.len() as i32 {
+        let params = start_data
+            .region(&other.to_string())
+            .ok_or(format!("Region {} not found in level {}", other, level))?;
+        let other_schedule = if other == region {
+            schedule.clone()
+        } else {
+            saved_schedules
+                .iter()
+                .find(|(id, _)| *id == other)
+                .map_or_else(Schedule::new, |(_, saved)| saved.0.clone())
+        };
+        regions.push(config(params, &other_schedule));
+    }
+    Ok(LevelRegions {
+        regions,
+        mobility: Some(mobility),
+        index: region as usize - 1,
+        solver: start_data.solver,
+        triggers: start_data.triggers,
+    })
+}
+
+/// Seeded per region so that every request replays the same outbreak
+fn region_seed(status_id: i32, region: i32) -> u64 {
+    ((status_id as u64) << 32) ^ region as u64
+}
+
+/// A region simulated over the whole level, with the triggers of the level it sets off
+pub struct RegionRun {
+    pub trajectory: Vec<State>,
+    pub triggered: Vec<TriggerResponse>,
+}
+
+/// Simulates a region over the whole level following its schedule. Regions of levels with a
+/// mobility matrix are integrated together, randomized levels simulate lone regions
+/// stochastically.
+pub fn simulate_region(
+    conn: &PgConnection,
+    user: &models::User,
+    status_id: i32,
+    region: i32,
+    schedule: &Schedule,
+) -> Result<RegionRun, DbError> {
+    let mut level = level_regions(conn, user.curlevel, status_id, region, schedule)?;
+    let thresholds = level
+        .triggers
+        .iter()
+        .map(|trigger| trigger.threshold.clone())
+        .collect::<Vec<_>>();
+    // Only lone deterministic regions can have their crossings located on the integrator
+    let run = match level.mobility {
+        Some(mobility) => {
+            let mut trajectories = simulate_coupled(&level.regions, &mobility, level.solver)?;
+            with_crossings(trajectories.swap_remove(level.index), &thresholds)?
+        }
+        None => {
+            let config = level.regions.swap_remove(level.index);
+            if user.is_randomized {
+                let mut backend = TauLeaping::new(POPULATION, region_seed(status_id, region));
+                with_crossings(simulate(config, &mut backend)?, &thresholds)?
+            } else {
+                simulate_watching(config, level.solver, thresholds)?
+            }
+        }
+    };
+    let triggered = run
+        .crossings
+        .iter()
+        .map(|crossing| {
+            let trigger = &level.triggers[crossing.threshold];
+            TriggerResponse {
+                day: crossing.time,
+                outcome: trigger.outcome,
+                headline: trigger.headline.clone(),
+            }
+        })
+        .collect();
+    Ok(RegionRun {
+        trajectory: run.trajectory,
+        triggered,
+    })
+}
+
+fn in_people(fractions: Vec<f64>) -> Vec<f64> {
+    fractions
+        .into_iter()
+        .map(|fraction| fraction * POPULATION)
+        .collect()
+}
+
+/// Simulation data sent for a region on `date`, from its run over the whole level simulated
+/// from `start` following `schedule`. The metrics cover the whole level while the curves start
+/// on `date`.
+pub fn simulator_response(
+    date: i32,
+    region: i32,
+    run: RegionRun,
+    start: SimulatorConfig,
+    schedule: &Schedule,
+    encoding: PayloadEncoding,
+) -> SimulatorResponse {
+    let RegionRun {
+        trajectory,
+        triggered,
+    } = run;
+    let config = config(start, schedule);
+    let params = schedule.parameters_at(&config.parameters, date.max(0) as f64);
+    let mut cases = detection::cases(&trajectory, &config.parameters, schedule);
+    let mut summary = Summary::new(&trajectory, &config, INFECTIOUS_THRESHOLD);
+    let start = (date.max(0) as usize).min(trajectory.len());
+    let trajectory = &trajectory[start..];
+    let overwhelmed_days = trajectory
+        .iter()
+        .zip(date..)
+        .filter(|(state, _)| params.hospitals_overwhelmed(state))
+        .map(|(_, day)| day)
+        .collect();
+    SimulatorResponse {
+        date,
+        region,
+        payload: Payload::new(trajectory, POPULATION, encoding),
+        ideal_reproduction_number: params.ideal_reproduction_number,
+        compliance_factor: params.compliance_factor,
+        recovery_rate: params.recovery_rate,
+        infection_rate: params.infection_rate,
+        vaccination_rate: params.vaccination_rate,
+        testing_rate: params.testing_rate,
+        hospital_capacity: params.hospital_capacity * POPULATION,
+        icu_capacity: params.icu_capacity * POPULATION,
+        overwhelmed_days,
+        metrics: MetricsResponse {
+            peak_infections: summary.peak_infections * POPULATION,
+            peak_day: summary.peak_day as i32,
+            final_size: summary.final_size * POPULATION,
+            deaths: summary.deaths * POPULATION,
+            effective_reproduction_number: summary.effective_reproduction_number.split_off(start),
+            days_above_threshold: summary.days_above_threshold as i32,
+            herd_immunity_day: summary.herd_immunity_day.map(|day| day as i32),
+        },
+        cases: CasesResponse {
+            true_cases: in_people(cases.true_cases.split_off(start)),
+            observed_cases: in_people(cases.observed_cases.split_off(start)),
+        },
+        triggers: triggered,
+    }
 }
 
 impl Seed {
@@ -37,7 +333,7 @@ impl Seed {
     pub fn handle(
         user: &extractors::Authenticated,
         conn: &PgConnection,
-    ) -> Result<WSResponse, DbError> {
+    ) -> Result<ServerMessage, DbError> {
         use crate::db::schema::users::dsl::*;
         let user = user.0.as_ref().unwrap();
         let user = users
@@ -46,30 +342,36 @@ impl Seed {
             .optional()?;
 
         let user = match user {
-            None => return Ok(WSResponse::Error("User not found".to_string())),
+            None => return Ok(ServerMessage::error(ErrorCode::NotFound, "User not found")),
             Some(y) => y,
         };
 
         let file = format!("src/game/levels/{}/seed.json", user.curlevel);
         let path = Path::new(&file);
-        let contents = fs::read_to_string(&path).unwrap_or_else(|e| {
-            error!("Couldn't read seed file: {}", e);
-            String::default()
-        });
-        if contents == String::default() {
-            return Ok(WSResponse::Error("Internal Server Error".to_string()));
+        let seed = fs::read_to_string(&path)
+            .map_err(DbError::from)
+            .and_then(|contents| Ok(serde_json::from_str(&contents)?));
+        match seed {
+            Ok(seed) => Ok(ServerMessage::Seed(seed)),
+            Err(e) => {
+                error!("Couldn't read seed file: {}", e);
+                Ok(ServerMessage::error(
+                    ErrorCode::InternalError,
+                    "Internal Server Error",
+                ))
+            }
         }
-        Ok(WSResponse::Seed(contents))
     }
 }
 
 impl Start {
     #[instrument(skip(conn))]
     pub fn handle(
-        payload: String,
+        request: Start,
         user: &extractors::Authenticated,
         conn: &PgConnection,
-    ) -> Result<WSResponse, DbError> {
+        encoding: PayloadEncoding,
+    ) -> Result<ServerMessage, DbError> {
         use crate::db::schema::status::dsl::*;
         use crate::db::schema::users;
         let user = user.0.as_ref().unwrap();
@@ -79,7 +381,7 @@ impl Start {
             .optional()?;
 
         let user = match user {
-            None => return Ok(WSResponse::Error("User not found".to_string())),
+            None => return Ok(ServerMessage::error(ErrorCode::NotFound, "User not found")),
             Some(y) => y,
         };
 
@@ -89,19 +391,19 @@ impl Start {
             None => {
                 let s_id = diesel::insert_into(status)
                     .default_values()
-                    .get_result::<(i32, i32, i32, i32)>(conn)?
-                    .0;
+                    .returning(id)
+                    .get_result::<i32>(conn)?;
 
                 info!("Creating a status entry with id: {}", s_id);
 
-                diesel::update((users::table).filter(users::email.eq(user.email)))
+                diesel::update((users::table).filter(users::email.eq(&user.email)))
                     .set(users::status.eq(s_id))
                     .execute(conn)?;
                 s_id
             }
         };
 
-        let region = serde_json::from_str::<Start>(&payload).unwrap().region;
+        let region = request.region;
 
         // Load the created regions for this user
         use crate::db::schema::regions;
@@ -123,13 +425,8 @@ impl Start {
                 // Initialise the region
                 let new_region_id = diesel::insert_into(regions::table)
                     .values(regions::region_id.eq(&region))
-                    .get_result::<(
-                        i32,
-                        i32,
-                        SimulatorParams,
-                        models::status::ActiveControlMeasures,
-                    )>(conn)?
-                    .0;
+                    .returning(regions::id)
+                    .get_result::<i32>(conn)?;
 
                 info!("Creating new region entry with id: {}", new_region_id);
 
@@ -147,100 +444,144 @@ impl Start {
         };
 
         if first_time {
-            let file = format!("src/game/levels/{}/start.json", user.curlevel);
-            let path = Path::new(&file);
-            let contents =
-                fs::read_to_string(&path).expect("Something went wrong reading the file");
-            let data = serde_json::from_str::<StartParams>(&contents).unwrap();
-
-            match data.params.get(&region.to_string()) {
+            match get_start_data(user.curlevel)?.region(&region.to_string()) {
                 Some(start_params) => {
                     // Update the status of this region
                     diesel::update(regions::table.filter(regions::id.eq(user_region_id)))
-                        .set(regions::simulation_params.eq(start_params))
+                        .set(
+                            regions::simulation_params
+                                .eq(models::status::StartConfig(start_params.clone())),
+                        )
                         .execute(conn)?;
 
-                    // simulate(&start_params)
-                    let sim = Simulator::new(
-                        &start_params.susceptible,
-                        &start_params.exposed,
-                        &start_params.infectious,
-                        &start_params.removed,
-                        &start_params.current_reproduction_number,
-                        &start_params.ideal_reproduction_number,
-                        &start_params.compliance_factor,
-                        &start_params.recovery_rate,
-                        &start_params.infection_rate,
-                    );
-
                     info!("Simulating Start with params: {:?}", start_params);
-                    let f = sim.simulate(0_f64, TOTAL_DAYS);
+                    let run =
+                        simulate_region(conn, &user, user_status_id, region, &Schedule::new())?;
 
-                    let payload = serialize_state(&f, POPULATION);
-                    Ok(WSResponse::Start(SimulatorResponse {
-                        date: 0,
+                    Ok(ServerMessage::Start(simulator_response(
+                        0,
                         region,
-                        payload,
-                        ideal_reproduction_number: start_params.ideal_reproduction_number,
-                        compliance_factor: start_params.compliance_factor,
-                        recovery_rate: start_params.recovery_rate,
-                        infection_rate: start_params.infection_rate,
-                    }))
+                        run,
+                        start_params,
+                        &Schedule::new(),
+                        encoding,
+                    )))
                 }
-                None => Ok(WSResponse::Error("Internal Server Error".to_string())),
+                None => Ok(ServerMessage::error(
+                    ErrorCode::InternalError,
+                    "Internal Server Error",
+                )),
             }
         } else {
-            let sim_params = (regions::table)
+            let schedule = (regions::table)
                 .filter(regions::id.eq(user_region_id))
-                .select(regions::simulation_params)
-                .first::<SimulatorParams>(conn)?;
-            let sim = Simulator::new(
-                &sim_params.susceptible,
-                &sim_params.exposed,
-                &sim_params.infectious,
-                &sim_params.removed,
-                &sim_params.current_reproduction_number,
-                &sim_params.ideal_reproduction_number,
-                &sim_params.compliance_factor,
-                &sim_params.recovery_rate,
-                &sim_params.infection_rate,
-            );
-
-            info!("Simulating Start with params: {:?}", sim_params);
-            let f = sim.simulate(0_f64, TOTAL_DAYS);
-
-            let payload = serialize_state(&f, POPULATION);
+                .select(regions::schedule)
+                .first::<models::status::ParameterSchedule>(conn)?;
 
             let date = status
                 .filter(id.eq(user_status_id))
                 .select(cur_date)
                 .first::<i32>(conn)?;
 
-            Ok(WSResponse::Start(SimulatorResponse {
+            info!("Simulating Start with schedule: {:?}", schedule.0);
+            let run = simulate_region(conn, &user, user_status_id, region, &schedule.0)?;
+
+            Ok(ServerMessage::Start(simulator_response(
                 date,
                 region,
-                payload,
-                ideal_reproduction_number: sim_params.ideal_reproduction_number,
-                compliance_factor: sim_params.compliance_factor,
-                recovery_rate: sim_params.recovery_rate,
-                infection_rate: sim_params.infection_rate,
-            }))
+                run,
+                get_start_params(user.curlevel, region)?,
+                &schedule.0,
+                encoding,
+            )))
         }
     }
 }
 
+impl Forecast {
+    #[instrument(skip(conn))]
+    pub fn handle(
+        request: Forecast,
+        user: &extractors::Authenticated,
+        conn: &PgConnection,
+        encoding: PayloadEncoding,
+    ) -> Result<ServerMessage, DbError> {
+        use crate::db::schema::{status, users};
+        let user = user.0.as_ref().unwrap();
+        let user = (users::table)
+            .filter(users::email.eq(user.email.clone()))
+            .first::<models::User>(conn)
+            .optional()?;
+        let user = match user {
+            None => return Ok(ServerMessage::error(ErrorCode::NotFound, "User not found")),
+            Some(y) => y,
+        };
+
+        let region = request.region;
+        let status_id = match user.status {
+            Some(s_id) => s_id,
+            None => {
+                return Ok(ServerMessage::error(
+                    ErrorCode::NotFound,
+                    "User status not found",
+                ))
+            }
+        };
+
+        let date = status::table
+            .filter(status::id.eq(status_id))
+            .select(status::cur_date)
+            .first::<i32>(conn)?
+            .max(0);
+        let schedule = get_schedule(conn, status_id, region)?;
+        let level = level_regions(conn, user.curlevel, status_id, region, &schedule)?;
+        // Randomized levels are forecast with their noise on top of the uncertain params
+        let population = if user.is_randomized {
+            Some(POPULATION)
+        } else {
+            None
+        };
+        // Already on a thread of the blocking pool, which other requests share
+        let ensemble = Ensemble::new(FORECAST_RUNS)
+            .with_seed(region_seed(status_id, region))
+            .with_threads(1);
+
+        info!("Forecasting region {} from day {}", region, date);
+        let bands = forecast(
+            date as u32,
+            &level,
+            population,
+            &ensemble,
+            FORECAST_PERTURBATIONS,
+        )?;
+        Ok(ServerMessage::Forecast(ForecastResponse {
+            date,
+            region,
+            runs: FORECAST_RUNS,
+            bands: bands
+                .trajectories
+                .iter()
+                .map(|band| {
+                    let start = (date as usize).min(band.len());
+                    Payload::new(&band[start..], POPULATION, encoding)
+                })
+                .collect(),
+            percentiles: bands.percentiles,
+        }))
+    }
+}
+
 impl ControlMeasure {
     #[instrument(skip(conn))]
     pub fn handle(
-        payload: String,
+        control_measure_request: ControlMeasure,
         user: &extractors::Authenticated,
         conn: &PgConnection,
-    ) -> Result<WSResponse, DbError> {
-        use crate::db::schema::{regions, regions_status, status, users};
+        encoding: PayloadEncoding,
+    ) -> Result<ServerMessage, DbError> {
+        use crate::db::schema::{regions, regions_status, users};
         use rand::{thread_rng, Rng};
 
-        // User input wrapped in a Result
-        let control_measure_request_result = serde_json::from_str::<ControlMeasure>(&payload);
         let user = user.0.as_ref().unwrap();
         let user = (users::table)
             .filter(users::email.eq(user.email.clone()))
@@ -249,518 +590,432 @@ impl ControlMeasure {
 
         // Check if user present
         let user = match user {
-            None => return Ok(WSResponse::Error("User not found".to_string())),
+            None => return Ok(ServerMessage::error(ErrorCode::NotFound, "User not found")),
             Some(y) => y,
         };
 
-        let res = match control_measure_request_result {
-            Err(_) => Ok(WSResponse::Error("Couldn't parse request".to_string())),
-            // If valid request
-            Ok(control_measure_request) => {
-                // Reads data from control measure file
-                let control_measure_name =
-                    &get_description(control_measure_request.name.clone(), user.curlevel);
-                let mut control_measure_message = match control_measure_name {
-                    Read::ControlNews(x) => x.apply.to_string(),
-                    _ => "Invalid control measure".to_string(),
-                };
-                let file = format!("src/game/levels/{}/control.json", user.curlevel);
-                let path = Path::new(&file);
-                let contents = match fs::read_to_string(&path) {
-                    Err(_) => return Ok(WSResponse::Error("Internal Server Error".to_string())),
-                    Ok(val) => val,
-                };
+        // Reads data from control measure file
+        let control_measure_data = get_control_measure_data(user.curlevel)?;
+        let control_measure_params = match control_measure_data.get(&control_measure_request.name) {
+            Some(control_measure_params) => control_measure_params,
+            None => {
+                return Ok(ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "Invalid control measure",
+                ))
+            }
+        };
+        let control_measure_name = get_description(&control_measure_request.name, user.curlevel)?;
+        let mut control_measure_message = match &control_measure_name {
+            Some(Read::ControlNews(x)) => x.apply.to_string(),
+            _ => "Invalid control measure".to_string(),
+        };
 
-                // Set date in user status
-                let status_id = match user.status {
-                    Some(s_id) => s_id,
-                    None => return Ok(WSResponse::Error("User status not found".to_string())),
-                };
+        // Set date in user status
+        let status_id = match user.status {
+            Some(s_id) => s_id,
+            None => {
+                return Ok(ServerMessage::error(
+                    ErrorCode::NotFound,
+                    "User status not found",
+                ))
+            }
+        };
 
-                let control_measure_data =
-                    serde_json::from_str::<HashMap<String, ControlMeasureParams>>(&contents)
-                        .unwrap();
+        let control_measure_failed = if user.is_randomized {
+            let mut rng = thread_rng();
+            let n: f32 = rng.gen_range(0.0..=1.0);
+            n <= control_measure_params.mess_up_chance
+        } else {
+            false
+        };
 
-                let control_measure_failed = if user.is_randomized {
-                    let mut rng = thread_rng();
-                    let n: f32 = rng.gen_range(0.0..=1.0);
-                    if let Some(val) = control_measure_data.get(&control_measure_request.name) {
-                        n <= val.mess_up_chance
-                    } else {
-                        return Ok(WSResponse::Error("Invalid event".to_string()));
-                    }
-                } else {
-                    false
-                };
+        let active_control_measure = (regions::table)
+            .inner_join(regions_status::table)
+            .filter(regions_status::status_id.eq(status_id))
+            .filter(regions::region_id.eq(control_measure_request.region as i32))
+            .select(regions::active_control_measures)
+            .load::<models::status::ActiveControlMeasures>(conn)?;
+        // The region's row is created when it is started
+        let active_control_measure = match active_control_measure.first() {
+            Some(active) => &active.0,
+            None => {
+                return Ok(ServerMessage::error(
+                    ErrorCode::NotFound,
+                    "Region hasn't been started",
+                ))
+            }
+        };
 
-                let active_control_measure = (regions::table)
-                    .inner_join(regions_status::table)
-                    .filter(regions_status::status_id.eq(status_id))
-                    .filter(regions::region_id.eq(control_measure_request.region as i32))
-                    .select(regions::active_control_measures)
-                    .load::<models::status::ActiveControlMeasures>(conn)?;
+        if let Some(control) = active_control_measure.get(&control_measure_request.name) {
+            if *control == control_measure_request.level {
+                info!("Control measure already applied at this level");
+                return Ok(ServerMessage::error(
+                    ErrorCode::ActionNotAllowed,
+                    "Control measure with same level already active",
+                ));
+            }
+        }
 
-                if let Some(control) = active_control_measure[0]
-                    .0
-                    .get(&control_measure_request.name)
-                {
-                    if *control == control_measure_request.level {
-                        info!("Control measure already applied at this level");
-                        return Ok(WSResponse::Error(
-                            "Control measure with same level already active".to_string(),
-                        ));
+        let date = advance_clock(conn, status_id, control_measure_request.cur_date)?;
+
+        let zero_delta: &Vec<f64> = &vec![0_f64; 4];
+
+        let (mut active_control_measures, existing_delta) =
+            match active_control_measure.get(&control_measure_request.name) {
+                Some(val) => {
+                    match control_measure_params.levels.get(val) {
+                        Some(y) => (active_control_measure.clone(), &y.params_delta),
+                        // Should never happen since the user can only have
+                        // a control measure if present in our file
+                        None => {
+                            return Ok(ServerMessage::error(
+                                ErrorCode::InternalError,
+                                "Internal Server Error",
+                            ))
+                        }
                     }
                 }
-
-                diesel::update(status::table)
-                    .filter(status::id.eq(status_id))
-                    .set(status::cur_date.eq(control_measure_request.cur_date))
-                    .execute(conn)?;
-
-                let zero_delta: &Vec<f64> = &vec![0_f64; 4];
-
-                let (mut active_control_measures, existing_delta) = if active_control_measure
-                    .is_empty()
+                None => (active_control_measure.clone(), zero_delta),
+            };
+
+        let (target_delta, cost) = match control_measure_request.action {
+            ControlMeasureAction::Apply => {
+                match control_measure_params
+                    .levels
+                    .get(&control_measure_request.level)
                 {
-                    let active_control_measures = if !control_measure_failed {
-                        let regions_row = diesel::insert_into(regions::table)
-                            .values(regions::region_id.eq(control_measure_request.region as i32))
-                            .get_result::<(
-                                i32,
-                                i32,
-                                SimulatorParams,
-                                models::status::ActiveControlMeasures,
-                            )>(conn)?;
-
-                        diesel::insert_into(regions_status::table)
-                            .values((
-                                regions_status::status_id.eq(status_id),
-                                regions_status::region_id.eq(regions_row.0),
-                            ))
-                            .execute(conn)?;
-                        regions_row.3 .0
-                    } else {
-                        HashMap::<String, i32>::new()
-                    };
-                    (active_control_measures, zero_delta)
-                } else {
-                    match active_control_measure[0]
-                        .0
-                        .get(&control_measure_request.name)
-                    {
-                        Some(val) => {
-                            match control_measure_data
-                                .get(&control_measure_request.name)
-                                .unwrap()
-                                .levels
-                                .get(val)
-                            {
-                                Some(y) => (active_control_measure[0].0.clone(), &y.params_delta),
-                                // Should never happen since the user can only have
-                                // a control measure if present in our file
-                                None => {
-                                    return Ok(WSResponse::Error(
-                                        "Internal Server Error".to_string(),
-                                    ))
-                                }
-                            }
+                    Some(control_measure_level_info) => {
+                        if control_measure_level_info.cost > user.money as u32 {
+                            info!("Not enough money");
+                            return Ok(ServerMessage::error(
+                                ErrorCode::ActionNotAllowed,
+                                "Not enough money",
+                            ));
                         }
-                        None => (active_control_measure[0].0.clone(), zero_delta),
-                    }
-                };
-
-                let (target_delta, cost) = match control_measure_request.action {
-                    ControlMeasureAction::Apply => {
-                        match control_measure_data.get(&control_measure_request.name) {
-                            Some(control_measure_params) => match control_measure_params
-                                .levels
-                                .get(&control_measure_request.level)
+                        let target = if !control_measure_failed {
+                            if let Some(x) =
+                                active_control_measures.get_mut(&control_measure_request.name)
                             {
-                                Some(control_measure_level_info) => {
-                                    if control_measure_level_info.cost > user.money as u32 {
-                                        info!("Not enough money");
-                                        return Ok(WSResponse::Error(
-                                            "Not enough money".to_string(),
-                                        ));
-                                    }
-                                    let target = if !control_measure_failed {
-                                        if let Some(x) = active_control_measures
-                                            .get_mut(&control_measure_request.name)
-                                        {
-                                            *x = control_measure_request.level;
-                                        } else {
-                                            active_control_measures.insert(
-                                                control_measure_request.name.clone(),
-                                                control_measure_request.level,
-                                            );
-                                        }
-                                        control_measure_level_info.params_delta.clone()
-                                    } else {
-                                        let target = control_measure_level_info
-                                            .params_delta
-                                            .iter()
-                                            .enumerate()
-                                            .map(|(ind, x)| match ind {
-                                                0 => -x,
-                                                1 => -x.abs(),
-                                                2 => -x.abs(),
-                                                3 => x.abs(),
-                                                _ => unreachable!(),
-                                            })
-                                            .collect::<Vec<f64>>();
-                                        target
-                                    };
-                                    (target, control_measure_level_info.cost)
-                                }
-                                None => {
-                                    return Ok(WSResponse::Error("Level not found".to_string()));
-                                }
-                            },
-                            None => {
-                                return Ok(WSResponse::Error(
-                                    "Control Measure not found".to_string(),
-                                ));
+                                *x = control_measure_request.level;
+                            } else {
+                                active_control_measures.insert(
+                                    control_measure_request.name.clone(),
+                                    control_measure_request.level,
+                                );
                             }
-                        }
-                    }
-                    ControlMeasureAction::Remove => {
-                        if existing_delta == zero_delta {
-                            return Ok(WSResponse::Error(
-                                "Control Measure was not applied".to_string(),
-                            ));
-                        }
-
-                        control_measure_message = match control_measure_name {
-                            Read::ControlNews(x) => x.remove.to_string(),
-                            _ => "Invalid control measure".to_string(),
+                            control_measure_level_info.params_delta.clone()
+                        } else {
+                            let target = control_measure_level_info
+                                .params_delta
+                                .iter()
+                                .enumerate()
+                                .map(|(ind, x)| match ind {
+                                    0 => -x,
+                                    1 => -x.abs(),
+                                    2 => -x.abs(),
+                                    3 => x.abs(),
+                                    _ => unreachable!(),
+                                })
+                                .collect::<Vec<f64>>();
+                            target
                         };
-                        active_control_measures.remove(&control_measure_request.name);
-                        (zero_delta.to_vec(), 0)
+                        (target, control_measure_level_info.cost)
                     }
-                };
-
-                let net_delta: Vec<f64> = existing_delta
-                    .iter()
-                    .zip(target_delta.iter())
-                    .map(|(a, b)| b - a)
-                    .collect();
-
-                let recvd_params = [
-                    control_measure_request.params.ideal_reproduction_number,
-                    control_measure_request.params.compliance_factor,
-                    control_measure_request.params.recovery_rate,
-                    control_measure_request.params.infection_rate,
-                ];
-
-                let zipped = zip!(net_delta, recvd_params, PARAM_LIMITS);
-                let changed_params: Vec<f64> = zipped
-                    .map(|(&a, (&b, &c))| {
-                        if a + b < c.0 {
-                            c.0
-                        } else if a + b > c.1 {
-                            c.1
-                        } else {
-                            a + b
-                        }
-                    })
-                    .collect();
-
-                info!(
-                    "Simulating Control Measure with params: {:?}\n{:?}",
-                    &control_measure_request.params, &changed_params
-                );
-                let (payload, susceptible, exposed, infectious, removed) = simulate(
-                    &control_measure_request.params,
-                    &changed_params,
-                    control_measure_request.cur_date,
-                );
-
-                conn.transaction::<_, diesel::result::Error, _>(|| {
-                    if !control_measure_failed {
-                        diesel::update(regions::table)
-                            .filter(
-                                regions::id.eq_any(
-                                    regions_status::table
-                                        .filter(regions_status::status_id.eq(status_id))
-                                        .select(regions_status::region_id)
-                                        .load::<i32>(conn)?,
-                                ),
-                            )
-                            .filter(regions::region_id.eq(control_measure_request.region as i32))
-                            .set((
-                                regions::active_control_measures.eq(
-                                    models::status::ActiveControlMeasures(active_control_measures),
-                                ),
-                                regions::simulation_params.eq(SimulatorParams {
-                                    susceptible,
-                                    exposed,
-                                    infectious,
-                                    removed,
-                                    current_reproduction_number: control_measure_request
-                                        .params
-                                        .current_reproduction_number,
-                                    ideal_reproduction_number: changed_params[0],
-                                    compliance_factor: changed_params[1],
-                                    recovery_rate: changed_params[2],
-                                    infection_rate: changed_params[3],
-                                }),
-                            ))
-                            .execute(conn)?;
+                    None => {
+                        return Ok(ServerMessage::error(ErrorCode::NotFound, "Level not found"));
                     }
-                    diesel::update(users::table)
-                        .filter(users::email.eq(user.email))
-                        .set(users::money.eq(user.money - cost as i32))
-                        .execute(conn)?;
-                    Ok(())
-                })?;
-                Ok(WSResponse::Control(ActionResponse {
-                    simulation_data: SimulatorResponse {
-                        date: control_measure_request.cur_date,
-                        region: control_measure_request.region as i32,
-                        payload,
-                        ideal_reproduction_number: changed_params[0],
-                        compliance_factor: changed_params[1],
-                        recovery_rate: changed_params[2],
-                        infection_rate: changed_params[3],
-                    },
-                    description: control_measure_message,
-                    is_success: !control_measure_failed,
-                }))
+                }
+            }
+            ControlMeasureAction::Remove => {
+                if !active_control_measures.contains_key(&control_measure_request.name) {
+                    return Ok(ServerMessage::error(
+                        ErrorCode::ActionNotAllowed,
+                        "Control Measure was not applied",
+                    ));
+                }
+
+                control_measure_message = match control_measure_name {
+                    Some(Read::ControlNews(x)) => x.remove,
+                    _ => "Invalid control measure".to_string(),
+                };
+                active_control_measures.remove(&control_measure_request.name);
+                (zero_delta.to_vec(), 0)
             }
         };
-        res
+
+        let net_delta: Vec<f64> = existing_delta
+            .iter()
+            .zip(target_delta.iter())
+            .map(|(a, b)| b - a)
+            .collect();
+
+        let region = control_measure_request.region as i32;
+        let start = get_start_params(user.curlevel, region)?;
+        let start_params = start.parameters.clone();
+        let day = date.max(0) as u32;
+        let mut schedule = get_schedule(conn, status_id, region)?;
+        // The params are moved from where the region's schedule has them on that day
+        let current_params = schedule.parameters_at(&start_params, day as f64);
+        let changed_params = apply_delta(&adjustable_params(current_params), &net_delta);
+        let sim_params = scheduled_parameters(
+            &start_params,
+            current_params,
+            &changed_params,
+            &active_control_measures,
+            &control_measure_data,
+        );
+        // A failed control measure still changes the params, only it isn't kept active
+        schedule.insert(day, sim_params.clone());
+
+        info!("Simulating Control Measure with params: {:?}", &sim_params);
+        let run = simulate_region(conn, &user, status_id, region, &schedule)?;
+        let simulation_data = simulator_response(date, region, run, start, &schedule, encoding);
+
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let region_ids = regions_status::table
+                .filter(regions_status::status_id.eq(status_id))
+                .select(regions_status::region_id)
+                .load::<i32>(conn)?;
+            let target = regions::table
+                .filter(regions::id.eq_any(region_ids))
+                .filter(regions::region_id.eq(region));
+            diesel::update(target.clone())
+                .set(regions::schedule.eq(models::status::ParameterSchedule(schedule)))
+                .execute(conn)?;
+            if !control_measure_failed {
+                diesel::update(target)
+                    .set(regions::active_control_measures.eq(
+                        models::status::ActiveControlMeasures(active_control_measures),
+                    ))
+                    .execute(conn)?;
+            }
+            diesel::update(users::table)
+                .filter(users::email.eq(user.email))
+                .set(users::money.eq(user.money - cost as i32))
+                .execute(conn)?;
+            Ok(())
+        })?;
+        Ok(ServerMessage::Control(ActionResponse {
+            simulation_data,
+            description: control_measure_message,
+            is_success: !control_measure_failed,
+        }))
     }
 }
 
 impl Event {
     #[instrument(skip(conn))]
     pub fn handle(
-        payload: String,
+        event: Event,
         user: &extractors::Authenticated,
         conn: &PgConnection,
-    ) -> Result<WSResponse, DbError> {
-        use crate::db::schema::users::dsl::*;
+        encoding: PayloadEncoding,
+    ) -> Result<ServerMessage, DbError> {
+        use crate::db::schema::users;
         let user = user.0.as_ref().unwrap();
-        let user = users
-            .filter(email.eq(user.email.clone()))
+        let user = (users::table)
+            .filter(users::email.eq(user.email.clone()))
             .first::<models::User>(conn)
             .optional()?;
         let user = match user {
-            None => return Ok(WSResponse::Error("User not found".to_string())),
+            None => return Ok(ServerMessage::error(ErrorCode::NotFound, "User not found")),
             Some(y) => y,
         };
 
         let user_status_id = match user.status {
             Some(x) => x,
-            None => return Ok(WSResponse::Error("Internal Server Error".to_string())),
+            None => {
+                return Ok(ServerMessage::error(
+                    ErrorCode::InternalError,
+                    "Internal Server Error",
+                ))
+            }
         };
 
-        let event = serde_json::from_str::<Event>(&payload);
-
-        match event {
-            Err(_) => Ok(WSResponse::Error("Couldn't parse request".to_string())),
-
-            Ok(event) => {
-                let file = format!("src/game/levels/{}/event.json", user.curlevel);
-                let path = Path::new(&file);
-                let contents =
-                    fs::read_to_string(&path).expect("Something  went wrong reading the file");
-                let event_data =
-                    serde_json::from_str::<HashMap<String, EventParams>>(&contents).unwrap();
+        let event_data = get_event_data(user.curlevel)?;
+        // Answers name the event by its id, which has to be one of the level's
+        if event.action != EventAction::Request && !event_data.contains_key(&event.id.to_string()) {
+            return Ok(ServerMessage::error(
+                ErrorCode::InvalidRequest,
+                "Invalid event",
+            ));
+        }
 
-                use crate::db::schema::status::dsl::*;
+        use crate::db::schema::status::dsl::*;
 
-                diesel::update(status)
-                    .filter(id.eq(user_status_id))
-                    .set(cur_date.eq(event.cur_date))
-                    .execute(conn)?;
+        let date = advance_clock(conn, user_status_id, event.cur_date)?;
 
-                match event.action {
-                    EventAction::Request => match status
-                        .filter(id.eq(user_status_id))
-                        .select(current_event)
-                        .first::<i32>(conn)
-                    {
-                        Ok(event_id) => {
-                            info!("Requested Event");
-                            if event_id == 0 {
-                                diesel::update(status)
-                                    .filter(id.eq(user_status_id))
-                                    .set((current_event.eq(1), postponed.eq(0)))
-                                    .execute(conn)?;
-                                match event_data.get("1") {
-                                    Some(data) => Ok(WSResponse::EventParams(EventParams {
-                                        id: event_id,
-                                        name: data.name.clone(),
-                                        description: data.description.clone(),
-                                        params_delta: data.params_delta.clone(),
-                                        region: data.region,
-                                        reward: data.reward,
-                                    })),
-                                    None => {
-                                        Ok(WSResponse::Error("Couldn't read the file".to_string()))
-                                    }
-                                }
-                            } else {
-                                match event_data.get(&event_id.to_string()) {
-                                    Some(data) => Ok(WSResponse::EventParams(EventParams {
-                                        id: event_id,
-                                        name: data.name.clone(),
-                                        description: data.description.clone(),
-                                        params_delta: data.params_delta.clone(),
-                                        region: data.region,
-                                        reward: data.reward,
-                                    })),
-                                    None => {
-                                        Ok(WSResponse::Error("Couldn't read the file".to_string()))
-                                    }
-                                }
-                            }
-                        }
-                        Err(_) => Ok(WSResponse::Error("Couldn't find user".to_string())),
-                    },
-                    EventAction::Accept => {
-                        info!("Accepting Event: {}", &event.id);
-
-                        let event_accept_message =
-                            &get_description(event.id.to_string(), user.curlevel);
-                        let event_accept_message = match event_accept_message {
-                            Read::EventNews(x) => x.accept.to_string(),
-                            _ => "Invalid Event".to_string(),
-                        };
-                        match event_data.get(&event.id.to_string()) {
-                            Some(data) => {
-                                let user_status =
-                                    status
-                                        .filter(id.eq(user_status_id))
-                                        .first::<(i32, i32, i32, i32)>(conn)?;
-                                let reward = if user_status.1 != event.id {
-                                    return Ok(WSResponse::Error(
-                                        "Cannot Accept event which wasn't requested".to_string(),
-                                    ));
-                                } else {
-                                    data.reward - user_status.2 * EVENT_POSTPONE_PENALTY
-                                };
-
-                                let recvd_params = [
-                                    event.params.ideal_reproduction_number,
-                                    event.params.compliance_factor,
-                                    event.params.recovery_rate,
-                                    event.params.infection_rate,
-                                ];
-
-                                let zipped = zip!(data.params_delta, recvd_params, PARAM_LIMITS);
-                                let changed_params: Vec<f64> = zipped
-                                    .map(|(&a, (&b, &c))| {
-                                        if a + b < c.0 {
-                                            c.0
-                                        } else if a + b > c.1 {
-                                            c.1
-                                        } else {
-                                            a + b
-                                        }
-                                    })
-                                    .collect();
-
-                                info!(
-                                    "Simulating Event with params: {:?}\n{:?}",
-                                    &event.params, &changed_params
-                                );
-                                let (payload, susceptible, exposed, infectious, removed) =
-                                    simulate(&event.params, &changed_params, event.cur_date);
-
-                                conn.transaction::<_, diesel::result::Error, _>(|| {
-                                    use crate::db::schema::{
-                                        regions, regions_status, status, users,
-                                    };
-                                    diesel::update(regions::table)
-                                        .filter(
-                                            regions::id.eq_any(
-                                                regions_status::table
-                                                    .filter(
-                                                        regions_status::status_id
-                                                            .eq(user_status_id),
-                                                    )
-                                                    .select(regions_status::region_id)
-                                                    .load::<i32>(conn)?,
-                                            ),
-                                        )
-                                        .filter(regions::region_id.eq(data.region))
-                                        .set(regions::simulation_params.eq(SimulatorParams {
-                                            susceptible,
-                                            exposed,
-                                            infectious,
-                                            removed,
-                                            current_reproduction_number:
-                                                event.params.current_reproduction_number,
-                                            ideal_reproduction_number: changed_params[0],
-                                            compliance_factor: changed_params[1],
-                                            recovery_rate: changed_params[2],
-                                            infection_rate: changed_params[3],
-                                        }))
-                                        .execute(conn)?;
-
-                                    diesel::update(users::table)
-                                        .filter(email.eq(user.email))
-                                        .set(money.eq(money + reward))
-                                        .execute(conn)?;
-
-                                    diesel::update(status::table)
-                                        .filter(id.eq(user_status_id))
-                                        .set((current_event.eq(current_event + 1), postponed.eq(0)))
-                                        .execute(conn)?;
-                                    Ok(())
-                                })?;
-
-                                Ok(WSResponse::Event(ActionResponse {
-                                    description: event_accept_message,
-                                    is_success: true,
-                                    simulation_data: SimulatorResponse {
-                                        date: event.cur_date,
-                                        region: data.region,
-                                        payload,
-                                        ideal_reproduction_number: changed_params[0],
-                                        compliance_factor: changed_params[1],
-                                        recovery_rate: changed_params[2],
-                                        infection_rate: changed_params[3],
-                                    },
-                                }))
-                            }
-                            None => Ok(WSResponse::Error("Invalid request sent".to_string())),
-                        }
-                    }
-                    EventAction::Decline => {
-                        info!("Declined Event: {}", &event.id);
-                        let event_decline_message =
-                            &get_description(event.id.to_string(), user.curlevel);
-                        let event_decline_message = match event_decline_message {
-                            Read::ControlNews(_) => "Invalid Event".to_string(),
-                            Read::EventNews(x) => x.reject.to_string(),
-                            Read::Bs(_) => "Invalid Event".to_string(),
-                        };
+        match event.action {
+            EventAction::Request => match status
+                .filter(id.eq(user_status_id))
+                .select(current_event)
+                .first::<i32>(conn)
+            {
+                Ok(event_id) => {
+                    info!("Requested Event");
+                    if event_id == 0 {
                         diesel::update(status)
                             .filter(id.eq(user_status_id))
-                            .set((current_event.eq(current_event + 1), postponed.eq(0)))
+                            .set((current_event.eq(1), postponed.eq(0)))
                             .execute(conn)?;
-                        Ok(WSResponse::Ok(event_decline_message))
+                        match event_data.get("1") {
+                            Some(data) => Ok(ServerMessage::EventParams(EventParams {
+                                id: event_id,
+                                ..data.clone()
+                            })),
+                            None => Ok(ServerMessage::error(
+                                ErrorCode::InternalError,
+                                "Couldn't read the file",
+                            )),
+                        }
+                    } else {
+                        match event_data.get(&event_id.to_string()) {
+                            Some(data) => Ok(ServerMessage::EventParams(EventParams {
+                                id: event_id,
+                                ..data.clone()
+                            })),
+                            None => Ok(ServerMessage::error(
+                                ErrorCode::InternalError,
+                                "Couldn't read the file",
+                            )),
+                        }
                     }
-                    EventAction::Postpone => {
-                        info!("Postponed Event: {}", &event.id);
-                        let event_postpone_message =
-                            &get_description(event.id.to_string(), user.curlevel);
-                        let event_postpone_message = match event_postpone_message {
-                            Read::ControlNews(_) => "Invalid Event".to_string(),
-                            Read::EventNews(x) => x.postpone.to_string(),
-                            Read::Bs(_) => "Invalid Event".to_string(),
-                        };
-                        diesel::update(status)
+                }
+                Err(_) => Ok(ServerMessage::error(
+                    ErrorCode::NotFound,
+                    "Couldn't find user",
+                )),
+            },
+            EventAction::Accept => {
+                info!("Accepting Event: {}", &event.id);
+
+                let event_accept_message = get_description(&event.id.to_string(), user.curlevel)?;
+                let event_accept_message = match event_accept_message {
+                    Some(Read::EventNews(x)) => x.accept,
+                    _ => "Invalid Event".to_string(),
+                };
+                match event_data.get(&event.id.to_string()) {
+                    Some(data) => {
+                        let (requested_event, times_postponed) = status
                             .filter(id.eq(user_status_id))
-                            .set(postponed.eq(postponed + 1))
-                            .execute(conn)?;
-                        Ok(WSResponse::Ok(event_postpone_message))
+                            .select((current_event, postponed))
+                            .first::<(i32, i32)>(conn)?;
+                        let reward = if requested_event != event.id {
+                            return Ok(ServerMessage::error(
+                                ErrorCode::ActionNotAllowed,
+                                "Cannot Accept event which wasn't requested",
+                            ));
+                        } else {
+                            data.reward - times_postponed * EVENT_POSTPONE_PENALTY
+                        };
+
+                        // The event's changes are saved to the region's row, created when it
+                        // is started
+                        let region_row = {
+                            use crate::db::schema::{regions, regions_status};
+                            (regions::table)
+                                .inner_join(regions_status::table)
+                                .filter(regions_status::status_id.eq(user_status_id))
+                                .filter(regions::region_id.eq(data.region))
+                                .select(regions::id)
+                                .first::<i32>(conn)
+                                .optional()?
+                        };
+                        let region_row = match region_row {
+                            Some(region_row) => region_row,
+                            None => {
+                                return Ok(ServerMessage::error(
+                                    ErrorCode::NotFound,
+                                    "Region hasn't been started",
+                                ))
+                            }
+                        };
+
+                        let start = get_start_params(user.curlevel, data.region)?;
+                        let start_params = start.parameters.clone();
+                        let day = date.max(0) as u32;
+                        let mut schedule = get_schedule(conn, user_status_id, data.region)?;
+                        let current_params = schedule.parameters_at(&start_params, day as f64);
+                        let changed_params =
+                            apply_delta(&adjustable_params(current_params), &data.params_delta);
+                        let mut sim_params = scheduled_parameters(
+                            &start_params,
+                            current_params,
+                            &changed_params,
+                            &get_active_control_measures(conn, user_status_id, data.region)?,
+                            &get_control_measure_data(user.curlevel)?,
+                        );
+                        if data.variant.is_some() {
+                            sim_params.variant = data.variant.clone();
+                        }
+                        schedule.insert(day, sim_params.clone());
+
+                        info!("Simulating Event with params: {:?}", &sim_params);
+                        let run =
+                            simulate_region(conn, &user, user_status_id, data.region, &schedule)?;
+                        let simulation_data =
+                            simulator_response(date, data.region, run, start, &schedule, encoding);
+
+                        conn.transaction::<_, diesel::result::Error, _>(|| {
+                            use crate::db::schema::{regions, status, users};
+                            diesel::update(regions::table.filter(regions::id.eq(region_row)))
+                                .set(
+                                    regions::schedule
+                                        .eq(models::status::ParameterSchedule(schedule)),
+                                )
+                                .execute(conn)?;
+
+                            diesel::update(users::table)
+                                .filter(users::email.eq(user.email))
+                                .set(users::money.eq(users::money + reward))
+                                .execute(conn)?;
+
+                            diesel::update(status::table)
+                                .filter(id.eq(user_status_id))
+                                .set((current_event.eq(current_event + 1), postponed.eq(0)))
+                                .execute(conn)?;
+                            Ok(())
+                        })?;
+
+                        Ok(ServerMessage::Event(ActionResponse {
+                            description: event_accept_message,
+                            is_success: true,
+                            simulation_data,
+                        }))
                     }
+                    None => Ok(ServerMessage::error(
+                        ErrorCode::InvalidRequest,
+                        "Invalid request sent",
+                    )),
                 }
             }
+            EventAction::Decline => {
+                info!("Declined Event: {}", &event.id);
+                let event_decline_message = get_description(&event.id.to_string(), user.curlevel)?;
+                let event_decline_message = match event_decline_message {
+                    Some(Read::EventNews(x)) => x.reject,
+                    _ => "Invalid Event".to_string(),
+                };
+                diesel::update(status)
+                    .filter(id.eq(user_status_id))
+                    .set((current_event.eq(current_event + 1), postponed.eq(0)))
+                    .execute(conn)?;
+                Ok(ServerMessage::Ok(event_decline_message))
+            }
+            EventAction::Postpone => {
+                info!("Postponed Event: {}", &event.id);
+                let event_postpone_message = get_description(&event.id.to_string(), user.curlevel)?;
+                let event_postpone_message = match event_postpone_message {
+                    Some(Read::EventNews(x)) => x.postpone,
+                    _ => "Invalid Event".to_string(),
+                };
+                diesel::update(status)
+                    .filter(id.eq(user_status_id))
+                    .set(postponed.eq(postponed + 1))
+                    .execute(conn)?;
+                Ok(ServerMessage::Ok(event_postpone_message))
+            }
         }
     }
 }
@@ -768,60 +1023,148 @@ impl Event {
 impl Save {
     #[instrument(skip(conn))]
     pub fn handle(
-        payload: String,
+        save_request: Save,
         user: &extractors::Authenticated,
         conn: &PgConnection,
-    ) -> Result<WSResponse, DbError> {
-        use crate::db::schema::{regions, regions_status, status, users};
-        let save_request_result = serde_json::from_str::<Save>(&payload);
+    ) -> Result<ServerMessage, DbError> {
+        use crate::db::schema::users;
         let user = user.0.as_ref().unwrap();
         let user = (users::table)
             .filter(users::email.eq(user.email.clone()))
             .first::<models::User>(conn)
             .optional()?;
         let user = match user {
-            None => return Ok(WSResponse::Error("User not found".to_string())),
+            None => return Ok(ServerMessage::error(ErrorCode::NotFound, "User not found")),
             Some(y) => y,
         };
 
-        match save_request_result {
-            Err(_) => Ok(WSResponse::Error("Couldn't parse request".to_string())),
-            Ok(save_request) => {
-                let status_id = match user.status {
-                    Some(status_id) => status_id,
-                    None => return Ok(WSResponse::Error("Internal Server Error".to_string())),
-                };
+        let status_id = match user.status {
+            Some(status_id) => status_id,
+            None => {
+                return Ok(ServerMessage::error(
+                    ErrorCode::InternalError,
+                    "Internal Server Error",
+                ))
+            }
+        };
 
-                let save_params = SimulatorParams {
-                    susceptible: save_request.params.susceptible / POPULATION,
-                    exposed: save_request.params.exposed / POPULATION,
-                    infectious: save_request.params.infectious / POPULATION,
-                    removed: save_request.params.removed / POPULATION,
-                    current_reproduction_number: save_request.params.current_reproduction_number,
-                    ideal_reproduction_number: save_request.params.ideal_reproduction_number,
-                    compliance_factor: save_request.params.compliance_factor,
-                    recovery_rate: save_request.params.recovery_rate,
-                    infection_rate: save_request.params.infection_rate,
-                };
+        // The state of the regions is rebuilt from their schedules, only the date is kept
+        advance_clock(conn, status_id, save_request.cur_date)?;
+        Ok(ServerMessage::Info("Saving".to_string()))
+    }
+}
 
-                diesel::update(status::table)
-                    .filter(status::id.eq(status_id))
-                    .set(status::cur_date.eq(save_request.cur_date))
-                    .execute(conn)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::events::types::EventAction;
+    use crate::db::models::{Identity, TestUser};
+    use crate::db::schema::{status, users};
+    use test_manager::TestDbManager;
+
+    const MONEY: i32 = 1000;
+
+    /// A player of level 1 with 1000 money, who has requested `current_event`
+    fn player(conn: &PgConnection, current_event: i32) -> extractors::Authenticated {
+        let email = "player@example.com".to_string();
+        diesel::insert_into(users::table)
+            .values(TestUser {
+                firstname: "Test".to_string(),
+                lastname: "Player".to_string(),
+                password: String::new(),
+                email: email.clone(),
+                score: 0,
+                money: MONEY,
+                is_email_verified: true,
+            })
+            .execute(conn)
+            .unwrap();
+        let status_id = diesel::insert_into(status::table)
+            .values(status::current_event.eq(current_event))
+            .returning(status::id)
+            .get_result::<i32>(conn)
+            .unwrap();
+        diesel::update(users::table)
+            .set(users::status.eq(status_id))
+            .execute(conn)
+            .unwrap();
+        extractors::Authenticated(Some(Identity {
+            name: "Test Player".to_string(),
+            email,
+        }))
+    }
 
-                diesel::update(regions::table)
-                    .set(regions::simulation_params.eq(save_params))
-                    .filter(regions::region_id.eq(save_request.region as i32))
-                    .filter(
-                        regions::id.eq_any(
-                            regions_status::table
-                                .filter(regions_status::status_id.eq(status_id))
-                                .select(regions_status::region_id),
-                        ),
-                    )
-                    .execute(conn)?;
-                Ok(WSResponse::Info("Saving".to_string()))
-            }
+    fn error_code(message: ServerMessage) -> Option<ErrorCode> {
+        match message {
+            ServerMessage::Error(error) => Some(error.code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn descriptions_of_unknown_keys_are_none() {
+        assert!(get_description("1", 1).unwrap().is_some());
+        assert!(get_description("No such measure", 1).unwrap().is_none());
+    }
+
+    #[test]
+    #[ignore = "needs the test database"]
+    fn accepting_an_event_on_an_unstarted_region_changes_nothing() {
+        let db = TestDbManager::new();
+        let conn = db.conn_pool.get().unwrap();
+        // Event 1 of level 1 is requested, its region never started
+        let user = player(&conn, 1);
+        let event = Event {
+            cur_date: 0,
+            id: 1,
+            action: EventAction::Accept,
+        };
+        let response = Event::handle(event, &user, &conn, PayloadEncoding::default()).unwrap();
+        assert_eq!(error_code(response), Some(ErrorCode::NotFound));
+
+        let money = users::table
+            .select(users::money)
+            .first::<i32>(&*conn)
+            .unwrap();
+        let current_event = status::table
+            .select(status::current_event)
+            .first::<i32>(&*conn)
+            .unwrap();
+        assert_eq!(money, MONEY);
+        assert_eq!(current_event, 1);
+    }
+
+    #[test]
+    #[ignore = "needs the test database"]
+    fn unknown_control_measures_and_events_are_invalid_requests() {
+        let db = TestDbManager::new();
+        let conn = db.conn_pool.get().unwrap();
+        let user = player(&conn, 1);
+
+        let control_measure = ControlMeasure {
+            level: 1,
+            cur_date: 0,
+            name: "No such measure".to_string(),
+            region: 1,
+            action: ControlMeasureAction::Apply,
+        };
+        let response =
+            ControlMeasure::handle(control_measure, &user, &conn, PayloadEncoding::default())
+                .unwrap();
+        assert_eq!(error_code(response), Some(ErrorCode::InvalidRequest));
+
+        for action in [
+            EventAction::Accept,
+            EventAction::Decline,
+            EventAction::Postpone,
+        ] {
+            let event = Event {
+                cur_date: 0,
+                id: 999,
+                action,
+            };
+            let response = Event::handle(event, &user, &conn, PayloadEncoding::default()).unwrap();
+            assert_eq!(error_code(response), Some(ErrorCode::InvalidRequest));
         }
     }
 }
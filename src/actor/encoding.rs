@@ -0,0 +1,243 @@
+use crate::db::types::DbError;
+use serde::{Deserialize, Serialize};
+use virus_simulator::{
+    State, DEATHS, EXPOSED, EXPOSED_VARIANT, HOSPITALIZED, ICU, INFECTIOUS, INFECTIOUS_VARIANT,
+    REMOVED, REPRODUCTION_NUMBER, SUSCEPTIBLE, VACCINATED,
+};
+
+use crate::actor::utils::serialize_state;
+
+/// How trajectories are laid out in simulation payloads
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    /// A JSON string of one array per day holding the first five compartments, as sent before
+    /// encodings could be chosen, with the dead counted in R. Only picked through the
+    /// handshake, by clients that still need it.
+    Legacy,
+    /// One f32 array per compartment
+    #[default]
+    Columnar,
+    /// One array of whole people per compartment, each value the difference from the day
+    /// before. The reproduction number is sent in thousandths.
+    Delta,
+}
+
+/// How WS responses are framed
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameFormat {
+    /// Text frames holding JSON
+    #[default]
+    Json,
+    /// Binary MessagePack frames
+    MessagePack,
+    /// Binary CBOR frames
+    Cbor,
+}
+
+/// Encodings chosen by the client when connecting, e.g. `/ws/?payload=delta&format=messagepack`.
+/// The payload encoding can still be changed in the handshake.
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct Encoding {
+    #[serde(default)]
+    pub payload: PayloadEncoding,
+    #[serde(default)]
+    pub format: FrameFormat,
+}
+
+/// Values of every compartment, one array each
+#[derive(Serialize)]
+pub struct Columns<T> {
+    pub susceptible: Vec<T>,
+    pub exposed: Vec<T>,
+    pub infectious: Vec<T>,
+    pub removed: Vec<T>,
+    pub reproduction_number: Vec<T>,
+    pub deaths: Vec<T>,
+    pub vaccinated: Vec<T>,
+    pub hospitalized: Vec<T>,
+    pub icu: Vec<T>,
+    pub exposed_variant: Vec<T>,
+    pub infectious_variant: Vec<T>,
+}
+
+impl<T> Columns<T> {
+    /// `value` gets the trajectory and the index of a compartment and returns its column
+    fn from_fn(trajectory: &[State], value: impl Fn(&[State], usize) -> Vec<T>) -> Self {
+        Self {
+            susceptible: value(trajectory, SUSCEPTIBLE),
+            exposed: value(trajectory, EXPOSED),
+            infectious: value(trajectory, INFECTIOUS),
+            removed: value(trajectory, REMOVED),
+            reproduction_number: value(trajectory, REPRODUCTION_NUMBER),
+            deaths: value(trajectory, DEATHS),
+            vaccinated: value(trajectory, VACCINATED),
+            hospitalized: value(trajectory, HOSPITALIZED),
+            icu: value(trajectory, ICU),
+            exposed_variant: value(trajectory, EXPOSED_VARIANT),
+            infectious_variant: value(trajectory, INFECTIOUS_VARIANT),
+        }
+    }
+}
+
+/// A trajectory encoded for the client, counts are in people
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Payload {
+    Legacy(String),
+    Columnar(Columns<f32>),
+    Delta(Columns<i32>),
+}
+
+/// Compartments are scaled to people, the reproduction number isn't
+fn scale(compartment: usize, population: f64) -> f64 {
+    if compartment == REPRODUCTION_NUMBER {
+        1.0
+    } else {
+        population
+    }
+}
+
+impl Payload {
+    pub fn new(trajectory: &[State], population: f64, encoding: PayloadEncoding) -> Self {
+        match encoding {
+            PayloadEncoding::Legacy => Payload::Legacy(serialize_state(trajectory, population)),
+            PayloadEncoding::Columnar => {
+                Payload::Columnar(Columns::from_fn(trajectory, |trajectory, compartment| {
+                    let scale = scale(compartment, population);
+                    trajectory
+                        .iter()
+                        .map(|state| (state[compartment] * scale) as f32)
+                        .collect()
+                }))
+            }
+            PayloadEncoding::Delta => {
+                Payload::Delta(Columns::from_fn(trajectory, |trajectory, compartment| {
+                    let scale = if compartment == REPRODUCTION_NUMBER {
+                        1000.0
+                    } else {
+                        population
+                    };
+                    let mut previous = 0;
+                    trajectory
+                        .iter()
+                        .map(|state| {
+                            let value = (state[compartment] * scale).round() as i32;
+                            let delta = value - previous;
+                            previous = value;
+                            delta
+                        })
+                        .collect()
+                }))
+            }
+        }
+    }
+}
+
+impl FrameFormat {
+    pub fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, DbError> {
+        match self {
+            FrameFormat::Json => Ok(serde_json::to_vec(message)?),
+            FrameFormat::MessagePack => Ok(rmp_serde::to_vec_named(message)?),
+            FrameFormat::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(message, &mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POPULATION: f64 = 5000.0;
+
+    /// Values a delta column stands for, by adding up its differences
+    fn decode(deltas: &[i32]) -> Vec<i32> {
+        deltas
+            .iter()
+            .scan(0, |value, delta| {
+                *value += delta;
+                Some(*value)
+            })
+            .collect()
+    }
+
+    fn trajectory() -> Vec<State> {
+        (0..50)
+            .map(|day| {
+                let t = day as f64;
+                let mut state = State::zeros();
+                state[SUSCEPTIBLE] = 0.99 * (-t / 30.0).exp();
+                state[INFECTIOUS] = 0.3 * (-(t - 20.0).powi(2) / 50.0).exp();
+                state[REMOVED] = 1.0 - state[SUSCEPTIBLE] - state[INFECTIOUS];
+                state[REPRODUCTION_NUMBER] = 2.5 - t / 40.0;
+                state[DEATHS] = 0.001 * t;
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delta_columns_decode_to_the_rounded_values() {
+        let trajectory = trajectory();
+        let columns = match Payload::new(&trajectory, POPULATION, PayloadEncoding::Delta) {
+            Payload::Delta(columns) => columns,
+            _ => panic!("Not a delta payload"),
+        };
+        let expected = |compartment: usize, scale: f64| -> Vec<i32> {
+            trajectory
+                .iter()
+                .map(|state| (state[compartment] * scale).round() as i32)
+                .collect()
+        };
+        assert_eq!(
+            decode(&columns.susceptible),
+            expected(SUSCEPTIBLE, POPULATION)
+        );
+        assert_eq!(
+            decode(&columns.infectious),
+            expected(INFECTIOUS, POPULATION)
+        );
+        assert_eq!(decode(&columns.removed), expected(REMOVED, POPULATION));
+        assert_eq!(decode(&columns.deaths), expected(DEATHS, POPULATION));
+        assert_eq!(
+            decode(&columns.reproduction_number),
+            expected(REPRODUCTION_NUMBER, 1000.0)
+        );
+        assert!(decode(&columns.icu).iter().all(|&value| value == 0));
+    }
+
+    #[test]
+    fn legacy_payload_keeps_the_first_five_compartments_with_the_dead_in_r() {
+        let trajectory = trajectory();
+        let legacy = match Payload::new(&trajectory, POPULATION, PayloadEncoding::Legacy) {
+            Payload::Legacy(legacy) => legacy,
+            _ => panic!("Not a legacy payload"),
+        };
+        let days: Vec<Vec<f64>> = serde_json::from_str(&legacy).unwrap();
+        assert_eq!(days.len(), trajectory.len());
+        for (day, state) in days.iter().zip(&trajectory) {
+            assert_eq!(day.len(), 5);
+            let removed = (state[REMOVED] + state[DEATHS]) * POPULATION;
+            assert!((day[REMOVED] - removed).abs() < 1.0e-9);
+        }
+    }
+
+    #[test]
+    fn frames_decode_to_the_same_message() {
+        let message = serde_json::json!({ "type": "ping", "request_id": 3, "values": [1.5, 2.0] });
+        let json: serde_json::Value =
+            serde_json::from_slice(&FrameFormat::Json.encode(&message).unwrap()).unwrap();
+        let msgpack: serde_json::Value =
+            rmp_serde::from_slice(&FrameFormat::MessagePack.encode(&message).unwrap()).unwrap();
+        let cbor: serde_json::Value =
+            ciborium::de::from_reader(&FrameFormat::Cbor.encode(&message).unwrap()[..]).unwrap();
+        assert_eq!(json, message);
+        assert_eq!(msgpack, message);
+        assert_eq!(cbor, message);
+    }
+}
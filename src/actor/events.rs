@@ -1,2 +1 @@
 pub mod types;
-pub mod utils;
@@ -1,7 +1,8 @@
-use crate::actor::events::utils::enum_str;
-use diesel::pg::types::sql_types::Jsonb;
+use crate::actor::encoding::{Payload, PayloadEncoding};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use virus_simulator::level::Outcome;
+pub use virus_simulator::level::{ControlMeasureParams, EventParams};
+use virus_simulator::SimulatorConfig;
 
 #[derive(Serialize)]
 pub struct NewsResponse {
@@ -36,11 +37,67 @@ pub enum Read {
 pub struct SimulatorResponse {
     pub date: i32,
     pub region: i32,
-    pub payload: String,
+    pub payload: Payload,
     pub ideal_reproduction_number: f64,
     pub compliance_factor: f64,
     pub recovery_rate: f64,
     pub infection_rate: f64,
+    pub vaccination_rate: f64,
+    pub testing_rate: f64,
+    /// Hospital beds, in people
+    pub hospital_capacity: f64,
+    /// ICU beds, in people
+    pub icu_capacity: f64,
+    /// Days of the payload on which there are more patients than beds
+    pub overwhelmed_days: Vec<i32>,
+    pub metrics: MetricsResponse,
+    pub cases: CasesResponse,
+    /// Triggers of the level the region sets off over the whole level, in time order
+    pub triggers: Vec<TriggerResponse>,
+}
+
+/// A trigger of `start.json` set off by a region
+#[derive(Serialize)]
+pub struct TriggerResponse {
+    /// Day on which its threshold is crossed, with the time of day as the fractional part
+    pub day: f64,
+    pub outcome: Outcome,
+    pub headline: String,
+}
+
+/// New cases on each day of the payload, in people. Only the observed ones are known to the
+/// authorities, they depend on how much the region tests.
+#[derive(Serialize)]
+pub struct CasesResponse {
+    pub true_cases: Vec<f64>,
+    pub observed_cases: Vec<f64>,
+}
+
+/// Metrics of a region's epidemic over the whole level, in people and days
+#[derive(Serialize)]
+pub struct MetricsResponse {
+    pub peak_infections: f64,
+    pub peak_day: i32,
+    pub final_size: f64,
+    pub deaths: f64,
+    /// Effective reproduction number on each day of the payload
+    pub effective_reproduction_number: Vec<f64>,
+    /// Days on which more than 1% of the region is infectious
+    pub days_above_threshold: i32,
+    /// Day from which immunity alone keeps the epidemic shrinking, if it is reached
+    pub herd_immunity_day: Option<i32>,
+}
+
+/// Percentile bands of a region's trajectory from `date` on, across simulations of the rest of
+/// the level with uncertain params
+#[derive(Serialize)]
+pub struct ForecastResponse {
+    pub date: i32,
+    pub region: i32,
+    pub runs: usize,
+    /// Percentile of each band, in increasing order
+    pub percentiles: Vec<f64>,
+    pub bands: Vec<Payload>,
 }
 
 #[derive(Serialize)]
@@ -50,81 +107,152 @@ pub struct ActionResponse {
     pub is_success: bool,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct WSPayload {
-    event_type: String,
-    payload: String,
-}
-
-enum_str!(
-    enum WSResponse {
-        // News(NewsEvent),
-        Seed(String),
-        Info(String),
-        Start(SimulatorResponse),
-        Control(ActionResponse),
-        Event(ActionResponse),
-        EventParams(EventParams),
-        Error(String),
-        Ok(String),
-    }
-);
-
-#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Debug)]
-#[sql_type = "Jsonb"]
-pub struct SimulatorParams {
-    pub susceptible: f64,
-    pub exposed: f64,
-    pub infectious: f64,
-    pub removed: f64,
-    pub current_reproduction_number: f64,
-    pub ideal_reproduction_number: f64,
-    pub compliance_factor: f64,
-    pub recovery_rate: f64,
-    pub infection_rate: f64,
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidRequest,
+    /// The message couldn't be decrypted or isn't a [`ClientMessage`]
+    MalformedMessage,
+    /// A message was sent before the [`ClientMessage::Hello`] handshake
+    HandshakeRequired,
+    /// The client speaks a version of the protocol the server doesn't
+    UnsupportedVersion,
+    NotFound,
+    ActionNotAllowed,
+    InvalidSimulationParams,
+    SimulationFailed,
+    /// Every DB connection is in use
+    ServerBusy,
+    /// The response took too long, what was asked may still be done
+    Timeout,
+    InternalError,
+}
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Version of the WS protocol, bumped whenever messages change in a way clients would notice.
+/// Version 2 sends columnar payloads unless the client picks another encoding.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Reply to the handshake
+#[derive(Serialize)]
+pub struct WelcomeResponse {
+    pub version: u32,
+    /// Encoding of the simulation payloads from then on
+    pub payload: PayloadEncoding,
 }
 
+/// Messages sent by the client, e.g. `{"type": "Start", "payload": {"region": 1}}`, in an
+/// [`Envelope`]. The first one has to be [`ClientMessage::Hello`].
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload")]
+pub enum ClientMessage {
+    Hello(Hello),
+    Seed,
+    Start(Start),
+    Forecast(Forecast),
+    Control(ControlMeasure),
+    Event(Event),
+    Save(Save),
+}
+
+/// Messages sent by the server, tagged like [`ClientMessage`] whichever the frame format
+#[derive(Serialize)]
+#[serde(tag = "type", content = "payload")]
+pub enum ServerMessage {
+    Welcome(WelcomeResponse),
+    Seed(serde_json::Value),
+    Info(String),
+    Start(SimulatorResponse),
+    Forecast(ForecastResponse),
+    Control(ActionResponse),
+    Event(ActionResponse),
+    EventParams(EventParams),
+    Error(ErrorResponse),
+    Ok(String),
+}
+
+/// A message with the id the client gave it, which the response to it carries back so that the
+/// client can tell which of its requests it answers
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+/// The id of a message that couldn't be read otherwise
 #[derive(Deserialize)]
+pub struct RequestId {
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode, message: &str) -> Self {
+        ServerMessage::Error(ErrorResponse {
+            code,
+            message: message.to_string(),
+        })
+    }
+}
+
+/// Opens the connection with the version of the protocol the client speaks
+#[derive(Deserialize, Debug)]
+pub struct Hello {
+    pub version: u32,
+    /// Encoding of the simulation payloads, overriding the one chosen when connecting. The
+    /// only way to get [`PayloadEncoding::Legacy`].
+    #[serde(default)]
+    pub payload: Option<PayloadEncoding>,
+}
+
+#[derive(Deserialize, Debug)]
 pub struct Start {
     pub region: i32,
 }
 
-#[derive(Deserialize)]
-pub struct Level {
-    pub id: i32,
+#[derive(Deserialize, Debug)]
+pub struct Forecast {
     pub region: i32,
 }
 
 #[derive(Deserialize)]
-pub struct StartParams {
-    pub params: HashMap<String, SimulatorParams>,
+pub struct Level {
+    pub id: i32,
+    pub region: i32,
 }
 
-#[derive(Deserialize, PartialEq)]
+#[derive(Deserialize, Debug, PartialEq)]
 #[serde(tag = "action")]
 pub enum ControlMeasureAction {
     Apply,
     Remove,
 }
 
-#[derive(Deserialize)]
+/// The state of the regions is rebuilt from their schedules, so only the date is saved.
+/// Dates sent by the client are only requests, see [`crate::actor::controllers::advance_clock`].
+#[derive(Deserialize, Debug)]
 pub struct Save {
     pub cur_date: i32,
-    pub region: u32,
-    pub params: SimulatorParams,
 }
 
-#[derive(Deserialize)]
+/// Params the client used to send along are ignored, the server reads them from the schedule
+#[derive(Deserialize, Debug)]
 pub struct ControlMeasure {
     pub level: i32,
     pub cur_date: i32,
     pub name: String,
-    pub params: SimulatorParams,
     pub region: u32,
     pub action: ControlMeasureAction,
 }
 
-#[derive(Deserialize, PartialEq)]
+#[derive(Deserialize, Debug, PartialEq)]
 #[serde(tag = "action")]
 pub enum EventAction {
     Request,
@@ -133,48 +261,17 @@ pub enum EventAction {
     Postpone,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 pub struct Event {
     pub cur_date: i32,
     pub id: i32,
-    pub params: SimulatorParams,
     pub action: EventAction,
 }
 
-#[derive(Deserialize)]
-pub struct WSRequest {
-    pub kind: String,
-    pub region: u32,
-    pub payload: String,
-}
-
 pub struct Seed {}
 
 #[derive(Serialize, Deserialize)]
 pub struct SectionData {
     pub population: f64,
-    pub init_params: SimulatorParams,
-}
-
-#[derive(Deserialize)]
-pub struct ControlMeasureLevel {
-    pub params_delta: Vec<f64>,
-    pub cost: u32,
-}
-
-#[derive(Deserialize)]
-pub struct ControlMeasureParams {
-    pub description: String,
-    pub levels: HashMap<i32, ControlMeasureLevel>,
-    pub mess_up_chance: f32,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct EventParams {
-    pub id: i32,
-    pub name: String,
-    pub description: String,
-    pub params_delta: Vec<f64>,
-    pub region: i32,
-    pub reward: i32,
+    pub init_params: SimulatorConfig,
 }
@@ -1,72 +1,146 @@
+use crate::actor::encoding::{Encoding, FrameFormat, PayloadEncoding};
 use crate::db::types::PgPool;
 
 use crate::actor::events::types::{
-    ControlMeasure, Event, Save, Seed, Start, WSRequest, WSResponse,
+    ClientMessage, ControlMeasure, Envelope, ErrorCode, Event, Forecast, Hello, RequestId, Save,
+    Seed, ServerMessage, Start, WelcomeResponse, PROTOCOL_VERSION,
 };
 
 use crate::db::types::DbError;
 use crate::utils::decrypt_data;
 use actix::prelude::*;
 use actix::{Actor, StreamHandler};
+use actix_rt::time::timeout;
+use actix_web::error::BlockingError;
 use actix_web::web;
 pub use actix_web_actors::ws;
 use actix_web_actors::ws::{Message, ProtocolError};
 
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use crate::auth::extractors;
 
-use tracing::{error, instrument};
+use tracing::{error, instrument, Span};
+use virus_simulator::SimulationError;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest the client waits for a response. Work that takes longer can't be interrupted, it
+/// goes on in the background and its response is dropped. Kept below `CLIENT_TIMEOUT` as pings
+/// aren't read while waiting.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// Locks serializing the requests of each user, across all their connections. Work that timed
+/// out keeps the lock until it really finishes, so it can't race the requests after it.
+#[derive(Default)]
+pub struct UserLocks(Mutex<HashMap<String, Arc<Mutex<()>>>>);
+
+impl UserLocks {
+    fn get(&self, email: &str) -> Arc<Mutex<()>> {
+        // The maps hold no invariants a panic could break
+        let mut locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        locks.entry(email.to_string()).or_default().clone()
+    }
+}
 
 pub struct Game {
     heartbeat: Instant,
     pool: web::Data<PgPool>,
+    locks: web::Data<UserLocks>,
     user: extractors::Authenticated,
+    encoding: Encoding,
+    /// Version of the protocol agreed on in the handshake, `None` until then
+    version: Option<u32>,
 }
 
 impl Actor for Game {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        use crate::db::schema::users::dsl::*;
-        use diesel::prelude::*;
-
-        let conn = self.pool.get().expect("Couldn't get DB connection");
-
-        let auth_user = self.user.0.as_ref().unwrap();
-        diesel::update(users.filter(email.eq(auth_user.email.clone())))
-            .set(is_active.eq(true))
-            .execute(&*conn)
-            .expect("Couldn't set user status");
+        // Messages are only read once the user is marked active
+        let set_active = set_active(self.pool.clone(), self.user.clone(), true);
+        ctx.wait(set_active.into_actor(self).map(|res, _, ctx| {
+            if let Err(e) = res {
+                error!("Couldn't set user status: {}", e);
+                ctx.stop();
+            }
+        }));
         self.heartbeat(ctx);
     }
 
     fn stopped(&mut self, ctx: &mut Self::Context) {
-        use crate::db::schema::users::dsl::*;
-        use diesel::prelude::*;
+        let set_active = set_active(self.pool.clone(), self.user.clone(), false);
+        actix::spawn(async move {
+            if let Err(e) = set_active.await {
+                error!("Couldn't set user status: {}", e);
+            }
+        });
 
-        let conn = self.pool.get().expect("Couldn't get DB connection");
+        ctx.stop();
+    }
+}
 
-        let auth_user = self.user.0.as_ref().unwrap();
-        diesel::update(users.filter(email.eq(auth_user.email.clone())))
-            .set(is_active.eq(false))
-            .execute(&*conn)
-            .expect("Couldn't set user status");
+/// Marks whether the user has a connection open, on the blocking thread pool
+async fn set_active(
+    pool: web::Data<PgPool>,
+    user: extractors::Authenticated,
+    active: bool,
+) -> Result<(), BlockingError<DbError>> {
+    use crate::db::schema::users::dsl::*;
+    use diesel::prelude::*;
 
-        ctx.stop();
+    web::block(move || {
+        let conn = pool.get()?;
+        let auth_user = user.0.as_ref().ok_or("Not authenticated")?;
+        diesel::update(users.filter(email.eq(&auth_user.email)))
+            .set(is_active.eq(active))
+            .execute(&*conn)?;
+        Ok::<_, DbError>(())
+    })
+    .await
+}
+
+/// Handles a message once the handshake is done, on a thread of the blocking pool
+fn handle(
+    message: ClientMessage,
+    pool: &PgPool,
+    user: &extractors::Authenticated,
+    encoding: PayloadEncoding,
+) -> Result<ServerMessage, DbError> {
+    let conn = pool.get()?;
+    match message {
+        ClientMessage::Hello(hello) => Ok(ServerMessage::error(
+            ErrorCode::InvalidRequest,
+            &format!("Version {} was already agreed on", hello.version),
+        )),
+        ClientMessage::Seed => Seed::handle(user, &conn),
+        ClientMessage::Start(request) => Start::handle(request, user, &conn, encoding),
+        ClientMessage::Forecast(request) => Forecast::handle(request, user, &conn, encoding),
+        ClientMessage::Control(request) => ControlMeasure::handle(request, user, &conn, encoding),
+        ClientMessage::Event(request) => Event::handle(request, user, &conn, encoding),
+        ClientMessage::Save(request) => Save::handle(request, user, &conn),
     }
 }
 
 #[instrument(skip(res))]
-fn ws_response(res: Result<WSResponse, DbError>) -> WSResponse {
+fn ws_response(res: Result<ServerMessage, DbError>) -> ServerMessage {
     match res {
         Ok(x) => x,
         Err(e) => {
             error!("{}", e);
-            WSResponse::Error("Internal Server Error".to_string())
+            // No connection came free in time
+            if e.is::<r2d2::Error>() {
+                return ServerMessage::error(ErrorCode::ServerBusy, "Server busy, try again");
+            }
+            match e.downcast_ref::<SimulationError>() {
+                Some(SimulationError::InvalidInitialState(x)) => {
+                    ServerMessage::error(ErrorCode::InvalidSimulationParams, &x.to_string())
+                }
+                Some(_) => ServerMessage::error(ErrorCode::SimulationFailed, "Simulation failed"),
+                None => ServerMessage::error(ErrorCode::InternalError, "Internal Server Error"),
+            }
         }
     }
 }
@@ -84,30 +158,40 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Game {
             Ok(Message::Text(text)) => {
                 let text = match decrypt_data(&text) {
                     Ok(x) => x,
-                    Err(x) => {
-                        error!("Decrypt data: {}", x);
-                        "".to_string()
+                    Err(e) => {
+                        error!("Decrypt data: {}", e);
+                        let res = Envelope {
+                            request_id: None,
+                            message: ServerMessage::error(
+                                ErrorCode::MalformedMessage,
+                                "Couldn't decrypt message",
+                            ),
+                        };
+                        self.send(ctx, &res);
+                        return;
                     }
                 };
-                let request = serde_json::from_str::<WSRequest>(&text).unwrap_or(WSRequest {
-                    kind: "".to_string(),
-                    region: 0,
-                    payload: "".to_string(),
-                });
-
-                let conn = self.pool.get().expect("Couldn't get DB connection");
-
-                let res = match request.kind.as_str() {
-                    "Seed" => ws_response(Seed::handle(&self.user, &conn)),
-                    "Start" => ws_response(Start::handle(request.payload, &self.user, &conn)),
-                    "Control" => {
-                        ws_response(ControlMeasure::handle(request.payload, &self.user, &conn))
+                let res = match serde_json::from_str::<Envelope<ClientMessage>>(&text) {
+                    Ok(Envelope {
+                        request_id,
+                        message,
+                    }) => return self.respond(request_id, message, ctx),
+                    Err(e) => {
+                        error!("Malformed message: {}", e);
+                        // The id is still sent back when it can be read
+                        let request_id = serde_json::from_str::<RequestId>(&text)
+                            .ok()
+                            .and_then(|x| x.request_id);
+                        Envelope {
+                            request_id,
+                            message: ServerMessage::error(
+                                ErrorCode::MalformedMessage,
+                                &e.to_string(),
+                            ),
+                        }
                     }
-                    "Event" => ws_response(Event::handle(request.payload, &self.user, &conn)),
-                    "Save" => ws_response(Save::handle(request.payload, &self.user, &conn)),
-                    _ => WSResponse::Error("Invalid request sent".to_string()),
                 };
-                ctx.text(res.stringify())
+                self.send(ctx, &res);
             }
             _ => ctx.stop(),
         }
@@ -115,11 +199,128 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Game {
 }
 
 impl Game {
-    pub fn new(conn_pool: web::Data<PgPool>, user: extractors::Authenticated) -> Self {
+    pub fn new(
+        conn_pool: web::Data<PgPool>,
+        locks: web::Data<UserLocks>,
+        user: extractors::Authenticated,
+        encoding: Encoding,
+    ) -> Self {
         Self {
             heartbeat: Instant::now(),
             pool: conn_pool,
+            locks,
             user,
+            encoding,
+            version: None,
+        }
+    }
+
+    fn handshake(&mut self, hello: Hello) -> ServerMessage {
+        if hello.version != PROTOCOL_VERSION {
+            return ServerMessage::error(
+                ErrorCode::UnsupportedVersion,
+                &format!("Server speaks version {}", PROTOCOL_VERSION),
+            );
+        }
+        self.version = Some(hello.version);
+        if let Some(payload) = hello.payload {
+            self.encoding.payload = payload;
+        }
+        ServerMessage::Welcome(WelcomeResponse {
+            version: PROTOCOL_VERSION,
+            payload: self.encoding.payload,
+        })
+    }
+
+    /// Handles a message, in a span carrying the client's id for it
+    #[instrument(skip(self, message, ctx))]
+    fn respond(
+        &mut self,
+        request_id: Option<String>,
+        message: ClientMessage,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let res = match message {
+            ClientMessage::Hello(hello) => self.handshake(hello),
+            _ if self.version.is_none() => {
+                ServerMessage::error(ErrorCode::HandshakeRequired, "Send Hello first")
+            }
+            message => return self.respond_blocking(request_id, message, ctx),
+        };
+        self.send(
+            ctx,
+            &Envelope {
+                request_id,
+                message: res,
+            },
+        );
+    }
+
+    /// Handles a message off the actor's thread. The next message is read once the response
+    /// to this one is sent, and the work itself holds the user's lock, so that the actions of a
+    /// user don't race each other even after a timeout.
+    fn respond_blocking(
+        &mut self,
+        request_id: Option<String>,
+        message: ClientMessage,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let (pool, user) = (self.pool.clone(), self.user.clone());
+        let lock = self.user.0.as_ref().map(|x| self.locks.get(&x.email));
+        let encoding = self.encoding.payload;
+        let span = Span::current();
+        let work = web::block(move || {
+            let _guard = lock
+                .as_ref()
+                .map(|x| x.lock().unwrap_or_else(|e| e.into_inner()));
+            span.in_scope(|| handle(message, &pool, &user, encoding))
+        });
+        ctx.wait(
+            timeout(REQUEST_TIMEOUT, work)
+                .into_actor(self)
+                .map(move |res, act, ctx| {
+                    // Pings weren't read while waiting
+                    act.heartbeat = Instant::now();
+                    let res = match res {
+                        Ok(Ok(res)) => res,
+                        Ok(Err(BlockingError::Error(e))) => ws_response(Err(e)),
+                        Ok(Err(BlockingError::Canceled)) => {
+                            error!("Handling was canceled");
+                            ServerMessage::error(ErrorCode::InternalError, "Internal Server Error")
+                        }
+                        Err(_) => {
+                            error!("Timed out after {:?}", REQUEST_TIMEOUT);
+                            ServerMessage::error(ErrorCode::Timeout, "Request timed out")
+                        }
+                    };
+                    act.send(
+                        ctx,
+                        &Envelope {
+                            request_id,
+                            message: res,
+                        },
+                    );
+                }),
+        );
+    }
+
+    /// Sends `message` in the frame format chosen by the client
+    fn send(&self, ctx: &mut <Self as Actor>::Context, message: &Envelope<ServerMessage>) {
+        let format = self.encoding.format;
+        let frame = format.encode(message).or_else(|e| {
+            error!("Encode response: {}", e);
+            format.encode(&Envelope {
+                request_id: message.request_id.clone(),
+                message: ServerMessage::error(ErrorCode::InternalError, "Internal Server Error"),
+            })
+        });
+        match frame {
+            // JSON is always valid UTF-8
+            Ok(bytes) if format == FrameFormat::Json => {
+                ctx.text(String::from_utf8(bytes).expect("JSON isn't UTF-8"))
+            }
+            Ok(bytes) => ctx.binary(bytes),
+            Err(e) => error!("Encode error response: {}", e),
         }
     }
 
@@ -1,38 +1,92 @@
+use actix_web::error::BlockingError;
 use actix_web::{http::StatusCode, web, Error, HttpRequest, HttpResponse};
 
+use crate::actor::encoding::{Encoding, PayloadEncoding};
 use crate::actor::implementation;
+use crate::actor::UserLocks;
 use crate::auth;
 use crate::db::models::User;
 use crate::db::types::PgPool;
 use diesel::prelude::*;
 
-use tracing::{info, instrument};
+use tracing::{error, info, instrument};
 
-#[instrument(skip(r, stream, pool))]
+/// Why the user opening a socket couldn't be read
+#[derive(Debug)]
+enum Lookup {
+    /// No connection came free in time
+    Busy(r2d2::Error),
+    Query(diesel::result::Error),
+}
+
+#[instrument(skip(r, stream, pool, locks))]
 pub async fn ws_index(
     r: HttpRequest,
     stream: web::Payload,
     pool: web::Data<PgPool>,
+    locks: web::Data<UserLocks>,
     user: auth::extractors::Authenticated,
+    encoding: web::Query<Encoding>,
 ) -> Result<HttpResponse, Error> {
     use crate::db::schema::users::dsl::*;
 
-    let conn = pool.get().expect("Couldn't get DB connection");
-    let auth_user = user.0.as_ref().unwrap();
-    let auth_user = users
-        .filter(email.eq(auth_user.email.clone()))
-        .first::<User>(&*conn)
-        .expect("User not found");
+    let auth_user = match user.0.as_ref() {
+        Some(auth_user) => auth_user,
+        None => return Ok(HttpResponse::Ok().status(StatusCode::UNAUTHORIZED).finish()),
+    };
 
-    if user.is_none() {
-        return Ok(HttpResponse::Ok().status(StatusCode::UNAUTHORIZED).finish());
+    if encoding.payload == PayloadEncoding::Legacy {
+        info!("Legacy payloads are only negotiated in the handshake");
+        return Ok(HttpResponse::Ok().status(StatusCode::BAD_REQUEST).finish());
     }
 
+    // Checking out a connection can wait for one to come free, off the arbiter's thread
+    let lookup_pool = pool.clone();
+    let lookup_email = auth_user.email.clone();
+    let found = web::block(move || {
+        let conn = lookup_pool.get().map_err(Lookup::Busy)?;
+        users
+            .filter(email.eq(lookup_email))
+            .first::<User>(&*conn)
+            .optional()
+            .map_err(Lookup::Query)
+    })
+    .await;
+    let auth_user = match found {
+        Ok(Some(auth_user)) => auth_user,
+        Ok(None) => {
+            info!("User not found");
+            return Ok(HttpResponse::Ok().status(StatusCode::NOT_FOUND).finish());
+        }
+        Err(BlockingError::Error(Lookup::Busy(e))) => {
+            error!("Couldn't get DB connection: {}", e);
+            return Ok(HttpResponse::Ok()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .finish());
+        }
+        Err(BlockingError::Error(Lookup::Query(e))) => {
+            error!("Couldn't get user: {}", e);
+            return Ok(HttpResponse::Ok()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .finish());
+        }
+        Err(BlockingError::Canceled) => {
+            error!("User lookup was canceled");
+            return Ok(HttpResponse::Ok()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .finish());
+        }
+    };
+
     // Don't allow multiple simultaneous connections
     if auth_user.is_active {
         info!("Another session is active");
         return Ok(HttpResponse::Ok().status(StatusCode::FORBIDDEN).finish());
     }
 
-    implementation::ws::start(implementation::Game::new(pool, user), &r, stream)
+    implementation::ws::start(
+        implementation::Game::new(pool, locks, user, encoding.into_inner()),
+        &r,
+        stream,
+    )
 }
@@ -1,25 +1,45 @@
-use crate::actor::events::types::SimulatorParams;
-use virus_simulator::Simulator;
-use virus_simulator::State;
+use rand::Rng;
+use virus_simulator::ensemble::{Bands, Ensemble, Perturbation, Sample};
+use virus_simulator::level::Trigger;
+use virus_simulator::metapopulation::Metapopulation;
+use virus_simulator::stochastic::TauLeaping;
+use virus_simulator::threshold::{self, Run};
+use virus_simulator::{in_people, State, Threshold, DEATHS, REMOVED};
+use virus_simulator::{
+    Backend, ConfigError, Deterministic, Schedule, SimulationError, Simulator, SimulatorConfig,
+    Solver,
+};
 
-const POPULATION: f64 = 5000.0;
-const TOTAL_DAYS: f64 = 700.0;
+/// Length of a level in days
+pub const TOTAL_DAYS: f64 = 700.0;
+
+/// Every region a region is simulated with. In levels with a mobility matrix that is every
+/// region of the level, each following its own saved schedule, otherwise the region alone.
+pub struct LevelRegions {
+    pub regions: Vec<SimulatorConfig>,
+    pub mobility: Option<Vec<Vec<f64>>>,
+    /// Index of the region itself in `regions`
+    pub index: usize,
+    pub solver: Solver,
+    /// Triggers of the level, watched in the region
+    pub triggers: Vec<Trigger>,
+}
+
+/// Compartments sent in legacy payloads, those there were before more were added
+const LEGACY_COLUMNS: usize = 5;
 
 pub fn serialize_state(s: &[State], population: f64) -> String {
     // serilising the data
     let res = s
         .iter()
         .map(|state| {
+            let mut state = in_people(state, population);
+            // R counted the dead until they got their own compartment
+            state[REMOVED] += state[DEATHS];
             let values = state
                 .iter()
-                .enumerate()
-                .map(|(ind, state)| {
-                    if ind == 4 {
-                        state.to_string()
-                    } else {
-                        (state * population).to_string()
-                    }
-                })
+                .take(LEGACY_COLUMNS)
+                .map(|value| value.to_string())
                 .collect::<Vec<String>>()
                 .join(",");
             format!("[{}]", values)
@@ -29,45 +49,96 @@ pub fn serialize_state(s: &[State], population: f64) -> String {
     format!("[{}]", res)
 }
 
+/// Config of a region starting from its `start` config and following `schedule`
+pub fn config(start: SimulatorConfig, schedule: &Schedule) -> SimulatorConfig {
+    SimulatorConfig {
+        schedule: schedule.clone(),
+        ..start
+    }
+}
+
+/// Simulates a single region over the whole level
 pub fn simulate(
-    params: &SimulatorParams,
-    changed_params: &[f64],
-    cur_date: i32,
-) -> (String, f64, f64, f64, f64) {
-    let susceptible = params.susceptible / POPULATION;
-    let exposed = params.exposed / POPULATION;
-    let infectious = params.infectious / POPULATION;
-    let removed = params.removed / POPULATION;
+    config: SimulatorConfig,
+    backend: &mut dyn Backend,
+) -> Result<Vec<State>, SimulationError> {
+    backend.simulate(Simulator::new(config)?, 0_f64, TOTAL_DAYS)
+}
 
-    let sim = Simulator::new(
-        &susceptible,
-        &exposed,
-        &infectious,
-        &removed,
-        &params.current_reproduction_number,
-        &changed_params[0],
-        &changed_params[1],
-        &changed_params[2],
-        &changed_params[3],
-    );
+/// Simulates a single region deterministically over the whole level, locating the crossings
+/// of `thresholds` on the integrator
+pub fn simulate_watching(
+    config: SimulatorConfig,
+    solver: Solver,
+    thresholds: Vec<Threshold>,
+) -> Result<Run, SimulationError> {
+    let simulator = Simulator::new(config)?.with_thresholds(thresholds)?;
+    Deterministic::new(solver).run(simulator, 0_f64, TOTAL_DAYS)
+}
 
-    let f = sim.simulate(0_f64, TOTAL_DAYS - cur_date as f64);
-    (
-        serialize_state(&f, POPULATION),
-        susceptible,
-        exposed,
-        infectious,
-        removed,
-    )
+/// A trajectory over the whole level with the crossings of `thresholds` found between its days,
+/// for those that can't be located on an integrator
+pub fn with_crossings(
+    trajectory: Vec<State>,
+    thresholds: &[Threshold],
+) -> Result<Run, ConfigError> {
+    thresholds.iter().try_for_each(Threshold::validate)?;
+    Ok(Run {
+        crossings: threshold::crossings(&trajectory, 0_f64, thresholds),
+        trajectory,
+    })
 }
 
-#[macro_export]
-macro_rules! zip {
-    ($x: expr) => ($x);
-    ($x: expr, $($y: expr), +) => (
-        $x.iter().zip(
-            zip!($($y), +))
-    )
+/// Simulates every region of a level together, coupled through the mobility matrix.
+/// Each region's schedule also sets how much it is coupled to the others over time.
+pub fn simulate_coupled(
+    regions: &[SimulatorConfig],
+    mobility: &[Vec<f64>],
+    solver: Solver,
+) -> Result<Vec<Vec<State>>, SimulationError> {
+    let sim = regions
+        .iter()
+        .map(|config| Simulator::new(config.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Metapopulation::new(sim, mobility.to_vec())?
+        .with_solver(solver)
+        .simulate(0_f64, TOTAL_DAYS)
 }
 
-pub use zip;
+/// Percentile bands of a region's trajectory over the whole level, with the params used from
+/// `from_day` on scaled by factors drawn from `perturbations` on every run. A `population`
+/// makes the runs of lone regions stochastic.
+pub fn forecast(
+    from_day: u32,
+    level: &LevelRegions,
+    population: Option<f64>,
+    ensemble: &Ensemble,
+    perturbations: &[Perturbation],
+) -> Result<Bands, SimulationError> {
+    ensemble.run(|rng| {
+        let sample = Sample::draw(perturbations, rng);
+        let mut sim = level
+            .regions
+            .iter()
+            .map(|config| Simulator::new(sample.apply_from(config, from_day)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match (&level.mobility, population) {
+            (Some(mobility), _) => Ok(Metapopulation::new(sim, mobility.clone())?
+                .with_solver(level.solver)
+                .simulate(0_f64, TOTAL_DAYS)?
+                .swap_remove(level.index)),
+            (None, Some(population)) => TauLeaping::new(population, rng.gen()).simulate(
+                sim.swap_remove(level.index),
+                0_f64,
+                TOTAL_DAYS,
+            ),
+            (None, None) => Deterministic::new(level.solver).simulate(
+                sim.swap_remove(level.index),
+                0_f64,
+                TOTAL_DAYS,
+            ),
+        }
+    })
+}
@@ -1,7 +1,8 @@
-use crate::actor::events::types::SimulatorParams;
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::Write;
+use virus_simulator::{Schedule, SimulatorConfig};
 
 use diesel::pg::{types::sql_types::Jsonb, Pg};
 use diesel::serialize::Output;
@@ -27,14 +28,38 @@ impl ToSql<Jsonb, Pg> for ActiveControlMeasures {
     }
 }
 
-impl FromSql<Jsonb, Pg> for SimulatorParams {
+/// Parameter changes made to a region by control measures and events, by day
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Debug, Default)]
+#[sql_type = "Jsonb"]
+pub struct ParameterSchedule(pub Schedule);
+
+impl FromSql<Jsonb, Pg> for ParameterSchedule {
+    fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
+        let value = <serde_json::Value as FromSql<Jsonb, Pg>>::from_sql(bytes)?;
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+impl ToSql<Jsonb, Pg> for ParameterSchedule {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> diesel::serialize::Result {
+        let value = serde_json::to_value(self)?;
+        <serde_json::Value as ToSql<Jsonb, Pg>>::to_sql(&value, out)
+    }
+}
+
+/// Config a region started the level from, as read from the level's `start.json`
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Debug)]
+#[sql_type = "Jsonb"]
+pub struct StartConfig(pub SimulatorConfig);
+
+impl FromSql<Jsonb, Pg> for StartConfig {
     fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
         let value = <serde_json::Value as FromSql<Jsonb, Pg>>::from_sql(bytes)?;
         Ok(serde_json::from_value(value)?)
     }
 }
 
-impl ToSql<Jsonb, Pg> for SimulatorParams {
+impl ToSql<Jsonb, Pg> for StartConfig {
     fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> diesel::serialize::Result {
         let value = serde_json::to_value(self)?;
         <serde_json::Value as ToSql<Jsonb, Pg>>::to_sql(&value, out)
@@ -48,6 +73,10 @@ pub struct Status {
     pub cur_date: i32,
     pub current_event: i32,
     pub postponed: i32,
+    /// When the date last moved, see [`crate::actor::controllers::advance_clock`]
+    pub clock_updated_at: NaiveDateTime,
+    /// Score the attempt ended with, once it has
+    pub final_score: Option<i32>,
 }
 
 #[derive(Identifiable, Debug, Clone, Serialize, Deserialize, Queryable)]
@@ -63,6 +92,7 @@ pub struct RegionsStatus {
 pub struct Regions {
     pub id: i32,
     pub region_id: i32,
-    pub simulation_params: SimulatorParams,
+    pub simulation_params: StartConfig,
     pub active_control_measures: ActiveControlMeasures,
+    pub schedule: ParameterSchedule,
 }
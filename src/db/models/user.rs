@@ -25,6 +25,8 @@ pub struct User {
     pub retryattemptsleft: i32,
     pub is_level_active: bool,
     pub curr_level_score: i32,
+    /// Status of the last attempt ended, which keeps its score
+    pub last_attempt: Option<i32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Insertable)]
@@ -4,6 +4,7 @@ table! {
         region_id -> Int4,
         simulation_params -> Jsonb,
         active_control_measures -> Jsonb,
+        schedule -> Jsonb,
     }
 }
 
@@ -21,6 +22,8 @@ table! {
         current_event -> Int4,
         postponed -> Int4,
         cur_date -> Int4,
+        clock_updated_at -> Timestamp,
+        final_score -> Nullable<Int4>,
     }
 }
 
@@ -41,6 +44,7 @@ table! {
         retryattemptsleft -> Int4,
         is_level_active -> Bool,
         curr_level_score -> Int4,
+        last_attempt -> Nullable<Int4>,
     }
 }
 
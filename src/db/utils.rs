@@ -4,6 +4,10 @@ use dotenv::dotenv;
 use r2d2::Pool;
 pub use r2d2_diesel::ConnectionManager;
 use std::env;
+use std::time::Duration;
+
+/// How long a request waits for a free connection before failing
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub fn create_db_pool() -> PgPool {
     dotenv().expect("Can't load environment variables");
@@ -11,6 +15,7 @@ pub fn create_db_pool() -> PgPool {
     let db_name = env::var("DB_NAME").expect("DB_NAME must be set");
     let manager = ConnectionManager::<PgConnection>::new(&format!("{}/{}", db_base_url, db_name));
     Pool::builder()
+        .connection_timeout(CONNECTION_TIMEOUT)
         .build(manager)
         .expect("Failed to create pool")
 }
@@ -1,13 +1,19 @@
+use crate::actor::controllers::{
+    get_schedule, get_start_data, simulate_region, INFECTIOUS_THRESHOLD,
+};
 use crate::auth::extractors::Authenticated;
 use crate::db::models;
 use crate::db::models::status::ActiveControlMeasures;
-use crate::db::schema::{regions, regions_status, users};
+use crate::db::schema::{regions, regions_status, status, users};
 use crate::db::types::DbError;
 use crate::game::response;
 use crate::game::response::ActiveControlMeasuresResponse;
 
 use diesel::prelude::*;
 use diesel::PgConnection;
+use std::fs::File;
+use virus_simulator::analysis::Summary;
+use virus_simulator::SimulatorConfig;
 
 pub fn get_current_level(
     conn: &PgConnection,
@@ -81,41 +87,105 @@ pub fn get_active_control_measures(
     Ok(acm)
 }
 
-pub fn update_user_at_level_end(
+/// Metrics of every region of the user's current level, simulated from their saved schedules
+pub fn get_level_summaries(
     conn: &PgConnection,
-    user: Authenticated,
+    user: &models::User,
+) -> Result<Vec<Summary>, DbError> {
+    let user_status_id = user.status.ok_or("Game hasn't started")?;
+
+    let start_data = get_start_data(user.curlevel)?;
+    let mut summaries = Vec::with_capacity(start_data.params.len());
+    for (region, start) in start_data.regions() {
+        let region = region.parse::<i32>()?;
+        let schedule = get_schedule(conn, user_status_id, region)?;
+        let run = simulate_region(conn, user, user_status_id, region, &schedule)?;
+        let config = SimulatorConfig { schedule, ..start };
+        summaries.push(Summary::new(&run.trajectory, &config, INFECTIOUS_THRESHOLD));
+    }
+    Ok(summaries)
+}
+
+/// Score of an attempt from how its regions ended and the share of the starting money left
+fn level_score(summaries: &[Summary], money_left: f64) -> f64 {
+    // Averaged over the regions, as fractions of their population
+    let regions = summaries.len().max(1) as f64;
+    let deaths = summaries.iter().map(|x| x.deaths).sum::<f64>() / regions;
+    let caseload = summaries.iter().map(|x| x.final_size).sum::<f64>() / (2.0 * regions);
+
+    let deaths_weight = -20.0; // negative cuz more deaths means less score
+    let caseload_weight = -5.0; // same with caseload
+    let money_weight = 0.25; // positive cuz more money remaining means better score
+    let score_scale = 1000.0;
+
+    let performance_factor =
+        deaths * deaths_weight + caseload * caseload_weight + money_left * money_weight; // will be between [0 and sum_of_weights]
+
+    score_scale * (20.0 + performance_factor)
+}
+
+/// Ends the user's attempt at their current level and scores it from the server's own state.
+/// Ending an attempt again gives back the score it got, without counting it twice. `None` if
+/// the user has no attempt to end.
+pub fn end_level(conn: &PgConnection, user_email: String) -> Result<Option<i32>, DbError> {
+    conn.transaction::<_, DbError, _>(|| {
+        // Locked so that concurrent calls for the same attempt score it once
+        let user = (users::table)
+            .filter(users::email.eq(user_email))
+            .for_update()
+            .first::<models::User>(conn)?;
+        let attempt = match (user.is_level_active, user.status) {
+            (true, Some(attempt)) => attempt,
+            // Chosen but not started, there's nothing to score yet
+            (true, None) => return Ok(None),
+            (false, _) => {
+                return Ok(match user.last_attempt {
+                    Some(attempt) => status::table
+                        .find(attempt)
+                        .select(status::final_score)
+                        .first::<Option<i32>>(conn)?,
+                    None => None,
+                })
+            }
+        };
+
+        let file = File::open(format!("src/game/levels/{}/endLevel.json", user.curlevel))?;
+        let end_level_data: response::EndLevelData = serde_json::from_reader(file)?;
+        let start_money = end_level_data.start_money;
+        let summaries = get_level_summaries(conn, &user)?;
+        let score = level_score(&summaries, user.money as f64 / start_money) as i32;
+
+        diesel::update(status::table.find(attempt))
+            .set(status::final_score.eq(score))
+            .execute(conn)?;
+        update_user_at_level_end(conn, &user, attempt, score, start_money)?;
+        Ok(Some(score))
+    })
+}
+
+fn update_user_at_level_end(
+    conn: &PgConnection,
+    user: &models::User,
+    attempt: i32,
     attempt_score: i32,
     user_money: f64,
-) -> Result<String, DbError> {
+) -> Result<(), DbError> {
     use crate::db::schema::users::dsl::*;
-    let user_email = user.0.as_ref().map(|y| y.email.clone());
-
-    let curr_score: i32 = users
-        .filter(email.eq(user_email.as_ref().unwrap()))
-        .load::<models::User>(conn)
-        .unwrap()[0]
-        .curr_level_score;
-
-    let tries_left: i32 = users
-        .filter(email.eq(user_email.as_ref().unwrap()))
-        .load::<models::User>(conn)
-        .unwrap()[0]
-        .retryattemptsleft;
 
+    let curr_score = user.curr_level_score;
+    let tries_left = user.retryattemptsleft;
     let user_score = (curr_score * (3 - tries_left) + attempt_score) / (4 - tries_left);
 
-    match diesel::update(users.filter(email.eq(user_email.unwrap())))
+    diesel::update(users.find(user.id))
         .set((
             retryattemptsleft.eq(retryattemptsleft - 1),
             money.eq(user_money as i32),
             score.eq(score - curr_score + user_score),
             curr_level_score.eq(user_score),
             status.eq::<Option<i32>>(None),
+            last_attempt.eq(Some(attempt)),
             is_level_active.eq(false),
         ))
-        .execute(conn)
-    {
-        Ok(_) => Ok("Updated User".to_string()),
-        Err(e) => Err(DbError::from(e)),
-    }
+        .execute(conn)?;
+    Ok(())
 }
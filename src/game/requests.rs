@@ -14,15 +14,3 @@ pub struct LevelRequest {
 pub struct StartLevelRequest {
     pub level: i32,
 }
-
-#[derive(Deserialize, Debug)]
-pub struct EndLevelDecrypted {
-    pub infected: f64,
-    pub removed: f64,
-    pub money_left: f64,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct EndLevelRequest {
-    pub payload: String,
-}
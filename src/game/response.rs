@@ -39,7 +39,6 @@ pub struct ActiveControlMeasuresResponse {
 #[derive(Serialize, Deserialize)]
 pub struct EndLevelData {
     pub start_money: f64,
-    pub mortality: f64,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -1,13 +1,11 @@
 use crate::auth::extractors::Authenticated;
 use crate::db::types::PgPool;
 use crate::game::controllers::{
-    change_level_type, get_active_control_measures, get_current_level, update_user_at_level_end,
+    change_level_type, end_level as end_attempt, get_active_control_measures, get_current_level,
 };
 use crate::game::{requests, response};
-use crate::utils::decrypt_data;
 use actix_web::{get, http::StatusCode, post, web, Error, HttpResponse};
 use std::collections::HashMap;
-use std::fs::File;
 use tracing::{error, info, instrument};
 
 #[get("/dashboard")]
@@ -76,72 +74,32 @@ async fn active_control_measures(
 
 #[post("/end-level")]
 #[instrument(skip(pool))]
-async fn end_level(
-    user: Authenticated,
-    pool: web::Data<PgPool>,
-    data: web::Json<requests::EndLevelRequest>,
-) -> Result<HttpResponse, Error> {
-    let data = decrypt_data(&data.payload).map_err(|e| {
-        error!("Couldn't decrypt: {}", e);
-        HttpResponse::InternalServerError().json(response::EndLevelResponse {
-            message: "Failed".to_string(),
-            score: 0.0,
-        })
-    })?;
-    let data = serde_json::from_str::<requests::EndLevelDecrypted>(&data).map_err(|e| {
-        error!("Couldn't parse json: {}", e);
+async fn end_level(user: Authenticated, pool: web::Data<PgPool>) -> Result<HttpResponse, Error> {
+    let email = user.0.unwrap().email;
+    let score = web::block(move || {
+        let conn = pool.get()?;
+        end_attempt(&conn, email)
+    })
+    .await
+    .map_err(|e| {
+        error!("Couldn't end level: {}", e);
         HttpResponse::InternalServerError().json(response::EndLevelResponse {
             message: "Failed".to_string(),
             score: 0.0,
         })
     })?;
-    let email = user.0.as_ref().unwrap().email.clone();
-    let conn1 = pool.get().unwrap();
-    let (cur_level, _, _, _) = web::block(move || get_current_level(&conn1, email.clone()))
-        .await
-        .map_err(|e| {
-            error!("Couldn't get level: {}", e);
-            HttpResponse::InternalServerError().json(response::EndLevelResponse {
-                message: "Failed".to_string(),
-                score: 0.0,
-            })
-        })?;
-    let file = File::open(format!("src/game/levels/{}/endLevel.json", cur_level)).unwrap();
-    let end_level_data: response::EndLevelData = serde_json::from_reader(file).unwrap();
-    let mortality = end_level_data.mortality;
-    let population = 15000.0;
-    let start_money = end_level_data.start_money;
-    let deaths = (data.removed * mortality) / population;
-    let caseload = (data.infected + data.removed) / (2.0 * population);
-    let money_left = data.money_left / start_money;
-
-    let deaths_weight = -20.0; // negative cuz more deaths means less score
-    let caseload_weight = -5.0; // same with caseload
-    let money_weight = 0.25; // positive cuz more money remaining means better score
-    let score_scale = 1000.0;
-
-    let performance_factor =
-        deaths * deaths_weight + caseload * caseload_weight + money_left * money_weight; // will be between [0 and sum_of_weights]
-
-    let score = score_scale * (20.0 + performance_factor);
-
-    match update_user_at_level_end(&pool.get().unwrap(), user, score as i32, start_money) {
-        Ok(_) => {
+    match score {
+        Some(score) => {
             info!("User ended level successfully");
             Ok(HttpResponse::Ok().json(response::EndLevelResponse {
                 message: "Success".to_string(),
-                score,
+                score: score as f64,
             }))
         }
-        Err(e) => {
-            error!("Couldn't update user: {}", e);
-            Ok(
-                HttpResponse::InternalServerError().json(response::EndLevelResponse {
-                    message: "Failed".to_string(),
-                    score: 0.0,
-                }),
-            )
-        }
+        None => Ok(HttpResponse::BadRequest().json(response::EndLevelResponse {
+            message: "No active level".to_string(),
+            score: 0.0,
+        })),
     }
 }
 
@@ -48,12 +48,15 @@ async fn main() -> std::io::Result<()> {
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
     let pool = create_db_pool();
+    // Shared by the workers, so a user's requests are serialized whichever one serves them
+    let user_locks = web::Data::new(actor::UserLocks::default());
     let app_url = dotenv::var("APP_URL").unwrap();
 
     HttpServer::new(move || {
         App::new()
             // set up DB pool to be used with web::Data<Pool> extractor
             .data(pool.clone())
+            .app_data(user_locks.clone())
             .wrap(auth::middleware::CheckAuth {})
             .wrap(IdentityService::new(auth::middleware::cookie_policy()))
             .wrap(common_middleware::cors_config())
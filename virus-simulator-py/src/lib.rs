@@ -0,0 +1,142 @@
+//! Python bindings of `virus_simulator`, so that notebooks run the exact model the server runs.
+//!
+//! Configs and solvers are given as dicts or JSON strings in the format of `start.json`, and
+//! trajectories come back as NumPy arrays with a row per day and a column per compartment.
+use numpy::ndarray::Array2;
+use numpy::{IntoPyArray, PyArray2, PyReadonlyArray2};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use serde::de::DeserializeOwned;
+use virus_simulator::stochastic::TauLeaping;
+use virus_simulator::{
+    Backend, Deterministic, Simulator as RustSimulator, SimulatorConfig, Solver, State, STATE_SIZE,
+};
+
+/// Names of the columns of a trajectory, in order
+const COMPARTMENTS: [&str; STATE_SIZE] = [
+    "susceptible",
+    "exposed",
+    "infectious",
+    "removed",
+    "reproduction_number",
+    "deaths",
+    "vaccinated",
+    "hospitalized",
+    "icu",
+    "exposed_variant",
+    "infectious_variant",
+];
+
+/// Reads `value`, a dict or a JSON string, the way the server reads level files
+fn from_json<T: DeserializeOwned>(value: &Bound<'_, PyAny>) -> PyResult<T> {
+    let json: String = match value.extract() {
+        Ok(json) => json,
+        Err(_) => value
+            .py()
+            .import("json")?
+            .call_method1("dumps", (value,))?
+            .extract()?,
+    };
+    serde_json::from_str(&json).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+fn to_array<'py>(py: Python<'py>, trajectory: &[State]) -> Bound<'py, PyArray2<f64>> {
+    let values = trajectory
+        .iter()
+        .flat_map(|state| state.iter().copied())
+        .collect();
+    // The shape matches the number of values by construction
+    Array2::from_shape_vec((trajectory.len(), STATE_SIZE), values)
+        .unwrap()
+        .into_pyarray(py)
+}
+
+/// A region as configured in `start.json`, optionally with a `schedule` of parameter changes
+#[pyclass]
+struct Simulator {
+    config: SimulatorConfig,
+}
+
+#[pymethods]
+impl Simulator {
+    #[new]
+    fn new(config: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let config: SimulatorConfig = from_json(config)?;
+        config
+            .validate()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Ok(Self { config })
+    }
+
+    /// Simulates from `start_time` to `end_time` and returns the fractions of the population
+    /// in each compartment on every day. Integrated with `solver`, Dopri5 by default, unless a
+    /// `seed` is given, in which case the region is simulated stochastically with `population`
+    /// people.
+    #[pyo3(signature = (end_time = 700.0, start_time = 0.0, solver = None, seed = None, population = 5000.0))]
+    fn simulate<'py>(
+        &self,
+        py: Python<'py>,
+        end_time: f64,
+        start_time: f64,
+        solver: Option<&Bound<'py, PyAny>>,
+        seed: Option<u64>,
+        population: f64,
+    ) -> PyResult<Bound<'py, PyArray2<f64>>> {
+        let simulator = RustSimulator::new(self.config.clone())
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let trajectory = match seed {
+            Some(seed) => {
+                TauLeaping::new(population, seed).simulate(simulator, start_time, end_time)
+            }
+            None => {
+                let solver: Solver = solver.map_or(Ok(Solver::default()), from_json)?;
+                Deterministic::new(solver).simulate(simulator, start_time, end_time)
+            }
+        }
+        .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+        Ok(to_array(py, &trajectory))
+    }
+
+    /// The config as JSON, with the defaults filled in
+    fn config(&self) -> PyResult<String> {
+        serde_json::to_string(&self.config).map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+}
+
+/// Scales a trajectory to people in a region of `population` like the server does before
+/// sending it, leaving the reproduction number as it is
+#[pyfunction]
+fn in_people<'py>(
+    py: Python<'py>,
+    trajectory: PyReadonlyArray2<'py, f64>,
+    population: f64,
+) -> PyResult<Bound<'py, PyArray2<f64>>> {
+    let trajectory = trajectory.as_array();
+    if trajectory.ncols() != STATE_SIZE {
+        return Err(PyValueError::new_err(format!(
+            "Expected {} columns, got {}",
+            STATE_SIZE,
+            trajectory.ncols()
+        )));
+    }
+    let states: Vec<State> = trajectory
+        .rows()
+        .into_iter()
+        .map(|row| {
+            virus_simulator::in_people(&State::from_iterator(row.iter().copied()), population)
+        })
+        .collect();
+    Ok(to_array(py, &states))
+}
+
+#[pymodule]
+#[pyo3(name = "virus_simulator")]
+fn virus_simulator_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Simulator>()?;
+    m.add_function(wrap_pyfunction!(in_people, m)?)?;
+    m.add("COMPARTMENTS", COMPARTMENTS.to_vec())?;
+    for (index, name) in COMPARTMENTS.iter().enumerate() {
+        m.add(name.to_uppercase().as_str(), index)?;
+    }
+    Ok(())
+}
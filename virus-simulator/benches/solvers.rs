@@ -0,0 +1,113 @@
+//! Compares the accuracy and latency of the solvers on the regions of the shipped levels.
+//!
+//! Run with `cargo bench --bench solvers`. The error of a solver is the largest difference
+//! with a very tight Dop853 run on any compartment and day, as a fraction of the population.
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use virus_simulator::level::Start;
+use virus_simulator::{Backend, Deterministic, Simulator, SimulatorConfig, Solver, State};
+
+/// Days a level lasts on the server
+const DAYS: f64 = 700.0;
+const REPETITIONS: u32 = 20;
+
+fn solvers() -> Vec<(&'static str, Solver)> {
+    vec![
+        ("rk4, 1 step/day", Solver::Rk4 { steps_per_day: 1 }),
+        ("rk4, 4 steps/day", Solver::Rk4 { steps_per_day: 4 }),
+        (
+            "dopri5, 1e-10",
+            Solver::Dopri5 {
+                rtol: 1.0e-10,
+                atol: 1.0e-10,
+            },
+        ),
+        (
+            "dopri5, 1e-6",
+            Solver::Dopri5 {
+                rtol: 1.0e-6,
+                atol: 1.0e-6,
+            },
+        ),
+        (
+            "dop853, 1e-10",
+            Solver::Dop853 {
+                rtol: 1.0e-10,
+                atol: 1.0e-10,
+            },
+        ),
+        (
+            "rosenbrock, 1e-6",
+            Solver::Rosenbrock {
+                rtol: 1.0e-6,
+                atol: 1.0e-9,
+            },
+        ),
+    ]
+}
+
+fn simulate(solver: Solver, config: &SimulatorConfig) -> Vec<State> {
+    let simulator = Simulator::new(config.clone()).expect("Invalid level config");
+    Deterministic::new(solver)
+        .simulate(simulator, 0.0, DAYS)
+        .expect("Simulation failed")
+}
+
+fn max_error(trajectory: &[State], reference: &[State]) -> f64 {
+    trajectory
+        .iter()
+        .zip(reference)
+        .map(|(state, reference)| (state - reference).amax())
+        .fold(0.0, f64::max)
+}
+
+fn main() {
+    let levels_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../src/game/levels");
+    let mut levels: Vec<_> = fs::read_dir(&levels_dir)
+        .expect("Levels not found")
+        .filter_map(|entry| Some(entry.ok()?.path().join("start.json")))
+        .filter(|path| path.exists())
+        .collect();
+    levels.sort();
+
+    let mut configs = Vec::new();
+    for path in &levels {
+        let start: Start = serde_json::from_str(&fs::read_to_string(path).unwrap())
+            .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let mut regions: Vec<_> = start.params.into_iter().collect();
+        regions.sort_by(|a, b| a.0.cmp(&b.0));
+        configs.extend(regions.into_iter().map(|(_, config)| config));
+    }
+    println!(
+        "{} regions from {} levels, {} days each\n",
+        configs.len(),
+        levels.len(),
+        DAYS
+    );
+
+    let reference_solver = Solver::Dop853 {
+        rtol: 1.0e-12,
+        atol: 1.0e-12,
+    };
+    let references: Vec<_> = configs
+        .iter()
+        .map(|config| simulate(reference_solver, config))
+        .collect();
+
+    println!("{:<20}{:>16}{:>16}", "solver", "time per run", "max error");
+    for (name, solver) in solvers() {
+        let mut elapsed = Duration::ZERO;
+        let mut error: f64 = 0.0;
+        for (config, reference) in configs.iter().zip(&references) {
+            let start = Instant::now();
+            for _ in 0..REPETITIONS {
+                std::hint::black_box(simulate(solver, config));
+            }
+            elapsed += start.elapsed();
+            error = error.max(max_error(&simulate(solver, config), reference));
+        }
+        let per_run = elapsed / (REPETITIONS * configs.len() as u32);
+        println!("{:<20}{:>16?}{:>16.2e}", name, per_run, error);
+    }
+}
@@ -0,0 +1,230 @@
+//! Metrics derived from a trajectory. Days are counted from the first state of the trajectory
+//! and values are fractions of the population, like the states themselves.
+use crate::detection;
+use crate::{
+    SimulatorConfig, State, DEATHS, EXPOSED, EXPOSED_VARIANT, HOSPITALIZED, ICU, INFECTIOUS,
+    INFECTIOUS_VARIANT, REMOVED, REPRODUCTION_NUMBER, SUSCEPTIBLE, VACCINATED,
+};
+use serde::Serialize;
+
+/// Day with the most infectious people, of either strain, and how many there were
+pub fn peak(trajectory: &[State]) -> Option<(usize, f64)> {
+    trajectory
+        .iter()
+        .map(|state| state[INFECTIOUS] + state[INFECTIOUS_VARIANT])
+        .enumerate()
+        .fold(None, |peak, (day, infectious)| match peak {
+            Some((_, max)) if max >= infectious => peak,
+            _ => Some((day, infectious)),
+        })
+}
+
+/// Infections by the end of the trajectory: the people infected before its first state and
+/// every infection on the following days, `infections` being the new ones on each day. People
+/// whose immunity waned count again when reinfected, while they are no longer in any infected
+/// compartment on the last day.
+pub fn final_size(trajectory: &[State], infections: &[f64]) -> f64 {
+    let infected_before = trajectory.first().map_or(0.0, |state| {
+        [
+            EXPOSED,
+            INFECTIOUS,
+            REMOVED,
+            DEATHS,
+            HOSPITALIZED,
+            ICU,
+            EXPOSED_VARIANT,
+            INFECTIOUS_VARIANT,
+        ]
+        .iter()
+        .map(|&compartment| state[compartment])
+        .sum()
+    });
+    infected_before + infections.iter().sum::<f64>()
+}
+
+/// Fraction of the population that can still be infected, counting vaccinated people by how
+/// likely they are to be infected anyway
+fn susceptible_share(state: &State, vaccine_efficacy: f64) -> f64 {
+    state[SUSCEPTIBLE] + (1.0 - vaccine_efficacy) * state[VACCINATED]
+}
+
+/// Number of people an infectious person infects on each day of a trajectory simulated from
+/// `config` starting on day 0, given who is left to infect, the season and the strain they
+/// carry. With a variant it is averaged over the infectious people of both strains.
+pub fn effective_reproduction_number(trajectory: &[State], config: &SimulatorConfig) -> Vec<f64> {
+    trajectory
+        .iter()
+        .enumerate()
+        .map(|(day, state)| {
+            let params = config
+                .schedule
+                .parameters_at(&config.parameters, day as f64);
+            let baseline = state[REPRODUCTION_NUMBER] * config.seasonality.factor(day as f64);
+            let original = baseline * susceptible_share(state, params.vaccine_efficacy);
+            let variant = match &params.variant {
+                Some(variant) => variant,
+                None => return original,
+            };
+            // The variant also reaches the part of the immune it escapes
+            let efficacy = params.vaccine_efficacy * (1.0 - variant.immunity_escape);
+            let reachable =
+                susceptible_share(state, efficacy) + variant.immunity_escape * state[REMOVED];
+            let variant_number = variant.transmissibility * baseline * reachable;
+            let infectious = state[INFECTIOUS] + state[INFECTIOUS_VARIANT];
+            if infectious > 0.0 {
+                (original * state[INFECTIOUS] + variant_number * state[INFECTIOUS_VARIANT])
+                    / infectious
+            } else {
+                original
+            }
+        })
+        .collect()
+}
+
+/// Number of days on which the compartments together are above `threshold`
+pub fn days_above(trajectory: &[State], compartments: &[usize], threshold: f64) -> usize {
+    trajectory
+        .iter()
+        .filter(|state| compartments.iter().map(|&c| state[c]).sum::<f64>() > threshold)
+        .count()
+}
+
+/// First day on which immunity alone would keep the epidemic shrinking, i.e. the share left to
+/// infect drops below `1 / basic_reproduction_number`
+pub fn herd_immunity_day(
+    trajectory: &[State],
+    basic_reproduction_number: f64,
+    vaccine_efficacy: f64,
+) -> Option<usize> {
+    trajectory.iter().position(|state| {
+        susceptible_share(state, vaccine_efficacy) * basic_reproduction_number < 1.0
+    })
+}
+
+/// All the metrics of a trajectory at once
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub peak_infections: f64,
+    pub peak_day: usize,
+    pub final_size: f64,
+    pub deaths: f64,
+    pub effective_reproduction_number: Vec<f64>,
+    /// Days on which more than the threshold passed to [`Summary::new`] is infectious
+    pub days_above_threshold: usize,
+    pub herd_immunity_day: Option<usize>,
+}
+
+impl Summary {
+    /// Metrics of a trajectory simulated from `config` starting on day 0. The reproduction
+    /// number of the first state is taken as the basic one.
+    pub fn new(trajectory: &[State], config: &SimulatorConfig, infectious_threshold: f64) -> Self {
+        let parameters = |day: usize| {
+            config
+                .schedule
+                .parameters_at(&config.parameters, day as f64)
+        };
+        let params = parameters(trajectory.len().saturating_sub(1));
+        let (peak_day, peak_infections) = peak(trajectory).unwrap_or_default();
+        let basic_reproduction_number = trajectory
+            .first()
+            .map_or(0.0, |state| state[REPRODUCTION_NUMBER]);
+        Self {
+            peak_infections,
+            peak_day,
+            final_size: final_size(trajectory, &detection::infections(trajectory, parameters)),
+            deaths: trajectory.last().map_or(0.0, |state| state[DEATHS]),
+            effective_reproduction_number: effective_reproduction_number(trajectory, config),
+            days_above_threshold: days_above(
+                trajectory,
+                &[INFECTIOUS, INFECTIOUS_VARIANT],
+                infectious_threshold,
+            ),
+            herd_immunity_day: herd_immunity_day(
+                trajectory,
+                basic_reproduction_number,
+                params.vaccine_efficacy,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Seasonality, Variant};
+
+    fn config(waning_rate: f64) -> SimulatorConfig {
+        SimulatorConfig::builder()
+            .susceptible(0.99)
+            .exposed(0.0)
+            .infectious(0.01)
+            .removed(0.0)
+            .current_reproduction_number(2.5)
+            .ideal_reproduction_number(2.5)
+            .compliance_factor(0.1)
+            .recovery_rate(0.1)
+            .infection_rate(0.2)
+            .waning_rate(waning_rate)
+            .build()
+            .unwrap()
+    }
+
+    fn summary(config: &SimulatorConfig) -> (Vec<State>, Summary) {
+        let trajectory = crate::Simulator::new(config.clone())
+            .unwrap()
+            .simulate(0.0, 400.0)
+            .unwrap();
+        let summary = Summary::new(&trajectory, config, 0.01);
+        (trajectory, summary)
+    }
+
+    #[test]
+    fn final_size_matches_the_people_left_without_waning() {
+        let (trajectory, summary) = summary(&config(0.0));
+        let last = trajectory.last().unwrap();
+        assert!((summary.final_size - (1.0 - last[SUSCEPTIBLE])).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn final_size_counts_people_whose_immunity_waned() {
+        let (trajectory, summary) = summary(&config(0.02));
+        let last = trajectory.last().unwrap();
+        // Everyone who was ever out of S got there by infection, some of them more than once
+        assert!(summary.final_size > 1.0 - last[SUSCEPTIBLE] + 0.1);
+    }
+
+    #[test]
+    fn effective_reproduction_number_follows_the_season() {
+        let config = SimulatorConfig {
+            seasonality: Seasonality::Sinusoidal {
+                amplitude: 0.5,
+                phase: 0.0,
+                period: 365.0,
+            },
+            ..config(0.0)
+        };
+        let trajectory = vec![crate::Simulator::new(config.clone())
+            .unwrap()
+            .initial_state()];
+        let rt = effective_reproduction_number(&trajectory, &config);
+        assert!((rt[0] - 2.5 * 1.5 * 0.99).abs() < 1.0e-12);
+    }
+
+    #[test]
+    fn effective_reproduction_number_weighs_the_variant_by_its_infectious() {
+        let mut config = config(0.0);
+        config.parameters.variant = Some(Variant {
+            transmissibility: 2.0,
+            immunity_escape: 0.0,
+            importation_rate: 0.0,
+        });
+        let mut state = State::zeros();
+        state[SUSCEPTIBLE] = 0.5;
+        state[REPRODUCTION_NUMBER] = 2.0;
+        state[INFECTIOUS] = 0.01;
+        state[INFECTIOUS_VARIANT] = 0.03;
+        let rt = effective_reproduction_number(&[state], &config);
+        // A quarter carry the original strain at 1, the rest the variant at 2
+        assert!((rt[0] - (0.25 * 1.0 + 0.75 * 2.0)).abs() < 1.0e-12);
+    }
+}
@@ -0,0 +1,147 @@
+//! Fits a region to an observed outbreak and prints it as a `start.json` region entry.
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use virus_simulator::fitting::{fit, Observation};
+use virus_simulator::level::Start;
+use virus_simulator::Parameters;
+
+const USAGE: &str = "\
+Usage: fit_region <CSV> --population <N> [OPTIONS]
+
+The CSV has a day,infectious,removed row per observation, counts are in people.
+
+Options:
+    --population <N>     People in the region
+    --level <DIR>        Take the rates that aren't fitted from a region of this level
+    --region <ID>        Region of --level to take them from [default: 1]
+    -h, --help           Print this message";
+
+struct Options {
+    observations: PathBuf,
+    population: f64,
+    level_dir: Option<PathBuf>,
+    region: String,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut observations = None;
+        let mut population = None;
+        let mut level_dir = None;
+        let mut region = "1".to_string();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--population" => population = Some(value()?.parse()?),
+                "--level" => level_dir = Some(value()?.into()),
+                "--region" => region = value()?,
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg).into()),
+                _ if observations.is_none() => observations = Some(arg.into()),
+                _ => return Err(format!("Unexpected argument {}", arg).into()),
+            }
+        }
+        Ok(Options {
+            observations: observations.ok_or("Missing observations")?,
+            population: population.ok_or("Missing --population")?,
+            level_dir,
+            region,
+        })
+    }
+}
+
+fn read_observations(contents: &str) -> Result<Vec<Observation>, Box<dyn Error>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        // Skips a header
+        .filter(|(index, line)| !(*index == 0 && line.starts_with(char::is_alphabetic)))
+        .map(|(index, line)| {
+            let values = line.split(',').map(str::trim).collect::<Vec<_>>();
+            match values[..] {
+                [day, infectious, removed] => Ok(Observation {
+                    day: day.parse()?,
+                    infectious: infectious.parse()?,
+                    removed: removed.parse()?,
+                }),
+                _ => Err(format!("Line {} isn't day,infectious,removed", index + 1).into()),
+            }
+        })
+        .collect()
+}
+
+/// Rates that aren't fitted when no level is given. The compliance factor doesn't matter as
+/// regions start at their ideal reproduction number.
+fn default_template() -> Parameters {
+    Parameters {
+        ideal_reproduction_number: 1.0,
+        compliance_factor: 1.0,
+        recovery_rate: 0.1,
+        infection_rate: 0.2,
+        fatality_rate: 0.0,
+        vaccination_rate: 0.0,
+        vaccine_efficacy: 0.0,
+        mobility_factor: 1.0,
+        hospitalization_rate: 0.0,
+        icu_rate: 0.0,
+        hospital_stay_rate: 0.0,
+        icu_stay_rate: 0.0,
+        icu_fatality_rate: 0.0,
+        overflow_fatality_rate: 0.0,
+        hospital_capacity: 1.0,
+        icu_capacity: 1.0,
+        waning_rate: 0.0,
+        testing_rate: 0.0,
+        reporting_delay: 0.0,
+        variant: None,
+    }
+}
+
+fn run(options: Options) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(&options.observations)
+        .map_err(|e| format!("Couldn't read {}: {}", options.observations.display(), e))?;
+    let observations = read_observations(&contents)?;
+
+    let template = match &options.level_dir {
+        Some(level_dir) => {
+            let path = level_dir.join("start.json");
+            let start: Start = serde_json::from_str(&fs::read_to_string(&path)?)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            start
+                .params
+                .get(&options.region)
+                .ok_or(format!("Region {} not found", options.region))?
+                .parameters
+                .clone()
+        }
+        None => default_template(),
+    };
+
+    let fitted = fit(&observations, options.population, &template)?;
+    eprintln!(
+        "Fitted with a root mean square error of {:.1} people",
+        fitted.residual
+    );
+    println!("{}", serde_json::to_string_pretty(&fitted.config)?);
+    Ok(())
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(options) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,453 @@
+//! Plays a level without the game, for checking balance changes before they are deployed.
+//!
+//! Reads `start.json`, `control.json` and `event.json` from a level directory, applies an
+//! optional list of actions the way the server does and prints the metrics of every region.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use virus_simulator::analysis::Summary;
+use virus_simulator::level::{
+    adjustable_params, apply_delta, scheduled_parameters, ControlMeasureParams, EventParams, Start,
+};
+use virus_simulator::metapopulation::Metapopulation;
+use virus_simulator::stochastic::TauLeaping;
+use virus_simulator::{
+    Backend, Deterministic, Schedule, Simulator, SimulatorConfig, State, Variant,
+    REPRODUCTION_NUMBER,
+};
+
+const USAGE: &str = "\
+Usage: simulate_level <LEVEL_DIR> [OPTIONS]
+
+Options:
+    --actions <FILE>     JSON list of actions to play, e.g.
+                         [{\"action\": \"Apply\", \"day\": 20, \"region\": 1, \"name\": \"Awareness\", \"level\": 2},
+                          {\"action\": \"Remove\", \"day\": 90, \"region\": 1, \"name\": \"Awareness\"},
+                          {\"action\": \"Event\", \"day\": 40, \"id\": 1}]
+    --days <N>           Days to simulate [default: 700]
+    --population <N>     People per region [default: 5000]
+    --threshold <X>      Infectious fraction counted by days_above_threshold [default: 0.01]
+    --seed <N>           Simulate lone regions stochastically, like randomized levels
+    --output <FILE>      Write the trajectories of every region to FILE
+    --format <FORMAT>    csv or json [default: from the extension of FILE, else csv]
+    -h, --help           Print this message";
+
+/// An action of a player, as they would send it over the WS
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action")]
+enum Action {
+    Apply {
+        day: u32,
+        region: i32,
+        name: String,
+        level: i32,
+    },
+    Remove {
+        day: u32,
+        region: i32,
+        name: String,
+    },
+    /// Accepting an event, declined and postponed events don't change anything
+    Event {
+        day: u32,
+        id: i32,
+    },
+}
+
+impl Action {
+    fn day(&self) -> u32 {
+        match self {
+            Action::Apply { day, .. } | Action::Remove { day, .. } | Action::Event { day, .. } => {
+                *day
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Format {
+    Csv,
+    Json,
+}
+
+struct Options {
+    level_dir: PathBuf,
+    actions: Option<PathBuf>,
+    days: u32,
+    population: f64,
+    threshold: f64,
+    seed: Option<u64>,
+    output: Option<PathBuf>,
+    format: Option<Format>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut level_dir = None;
+        let mut options = Options {
+            level_dir: PathBuf::new(),
+            actions: None,
+            days: 700,
+            population: 5000.0,
+            threshold: 0.01,
+            seed: None,
+            output: None,
+            format: None,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--actions" => options.actions = Some(value()?.into()),
+                "--days" => options.days = value()?.parse()?,
+                "--population" => options.population = value()?.parse()?,
+                "--threshold" => options.threshold = value()?.parse()?,
+                "--seed" => options.seed = Some(value()?.parse()?),
+                "--output" => options.output = Some(value()?.into()),
+                "--format" => {
+                    options.format = Some(match value()?.as_str() {
+                        "csv" => Format::Csv,
+                        "json" => Format::Json,
+                        other => return Err(format!("Unknown format {}", other).into()),
+                    })
+                }
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg).into()),
+                _ if level_dir.is_none() => level_dir = Some(arg.into()),
+                _ => return Err(format!("Unexpected argument {}", arg).into()),
+            }
+        }
+        options.level_dir = level_dir.ok_or("Missing level directory")?;
+        Ok(options)
+    }
+
+    fn format(&self) -> Format {
+        self.format.unwrap_or_else(|| {
+            match self.output.as_ref().and_then(|output| output.extension()) {
+                Some(extension) if extension == "json" => Format::Json,
+                _ => Format::Csv,
+            }
+        })
+    }
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T, Box<dyn Error>> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// What the server keeps about a region while a level is played
+struct Region {
+    id: i32,
+    config: SimulatorConfig,
+    /// Ideal reproduction number, compliance factor, recovery rate and infection rate as the
+    /// player sees them
+    params: Vec<f64>,
+    active_control_measures: HashMap<String, i32>,
+    schedule: Schedule,
+}
+
+impl Region {
+    fn new(id: i32, config: SimulatorConfig) -> Self {
+        let params = adjustable_params(&config.parameters);
+        Self {
+            id,
+            config,
+            params,
+            active_control_measures: HashMap::new(),
+            schedule: Schedule::new(),
+        }
+    }
+
+    /// Moves the params by `delta` from `day` on, with the control measures now active and
+    /// `variant` if it comes in
+    fn change(
+        &mut self,
+        day: u32,
+        delta: &[f64],
+        variant: Option<&Variant>,
+        control_measure_data: &HashMap<String, ControlMeasureParams>,
+    ) {
+        self.params = apply_delta(&self.params, delta);
+        let mut parameters = scheduled_parameters(
+            &self.config.parameters,
+            self.schedule
+                .parameters_at(&self.config.parameters, day as f64),
+            &self.params,
+            &self.active_control_measures,
+            control_measure_data,
+        );
+        if let Some(variant) = variant {
+            parameters.variant = Some(variant.clone());
+        }
+        self.schedule.insert(day, parameters);
+    }
+
+    /// The start config following the schedule of the region
+    fn scheduled_config(&self) -> SimulatorConfig {
+        SimulatorConfig {
+            schedule: self.schedule.clone(),
+            ..self.config.clone()
+        }
+    }
+
+    fn simulator(&self) -> Result<Simulator, Box<dyn Error>> {
+        Ok(Simulator::new(self.scheduled_config())?)
+    }
+}
+
+fn params_delta<'a>(
+    control_measure_data: &'a HashMap<String, ControlMeasureParams>,
+    name: &str,
+    level: i32,
+) -> Result<&'a [f64], Box<dyn Error>> {
+    let control_measure = control_measure_data
+        .get(name)
+        .ok_or(format!("Control measure {} not found", name))?;
+    let level_info = control_measure
+        .levels
+        .get(&level)
+        .ok_or(format!("Level {} of {} not found", level, name))?;
+    Ok(&level_info.params_delta)
+}
+
+fn find(regions: &mut [Region], id: i32) -> Result<&mut Region, Box<dyn Error>> {
+    regions
+        .iter_mut()
+        .find(|region| region.id == id)
+        .ok_or_else(|| format!("Region {} not found", id).into())
+}
+
+/// Plays `action` on the regions like the Control and Event requests of the server
+fn play(
+    regions: &mut [Region],
+    action: &Action,
+    control_measure_data: &HashMap<String, ControlMeasureParams>,
+    event_data: &HashMap<String, EventParams>,
+) -> Result<(), Box<dyn Error>> {
+    match action {
+        Action::Apply {
+            day,
+            region,
+            name,
+            level,
+        } => {
+            let region = find(regions, *region)?;
+            let target = params_delta(control_measure_data, name, *level)?;
+            let existing = match region.active_control_measures.get(name) {
+                Some(active) if active == level => {
+                    return Err(format!("{} is already active at level {}", name, level).into())
+                }
+                Some(&active) => params_delta(control_measure_data, name, active)?.to_vec(),
+                None => vec![0.0; target.len()],
+            };
+            let net_delta: Vec<f64> = existing.iter().zip(target).map(|(a, b)| b - a).collect();
+            region.active_control_measures.insert(name.clone(), *level);
+            region.change(*day, &net_delta, None, control_measure_data);
+        }
+        Action::Remove { day, region, name } => {
+            let region = find(regions, *region)?;
+            let active = region
+                .active_control_measures
+                .remove(name)
+                .ok_or(format!("{} isn't active in region {}", name, region.id))?;
+            let net_delta: Vec<f64> = params_delta(control_measure_data, name, active)?
+                .iter()
+                .map(|x| -x)
+                .collect();
+            region.change(*day, &net_delta, None, control_measure_data);
+        }
+        Action::Event { day, id } => {
+            let event = event_data
+                .get(&id.to_string())
+                .ok_or(format!("Event {} not found", id))?;
+            let region = find(regions, event.region)?;
+            region.change(
+                *day,
+                &event.params_delta,
+                event.variant.as_ref(),
+                control_measure_data,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A region's trajectory and metrics, in people
+#[derive(Serialize)]
+struct RegionOutput<'a> {
+    region: i32,
+    summary: Summary,
+    trajectory: &'a [Vec<f64>],
+}
+
+fn in_people(trajectory: &[State], population: f64) -> Vec<Vec<f64>> {
+    trajectory
+        .iter()
+        .map(|state| {
+            state
+                .iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    if index == REPRODUCTION_NUMBER {
+                        *value
+                    } else {
+                        value * population
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Converts the compartments of a summary from fractions of the population to people
+fn summary_in_people(mut summary: Summary, population: f64) -> Summary {
+    summary.peak_infections *= population;
+    summary.final_size *= population;
+    summary.deaths *= population;
+    summary
+}
+
+fn write_csv(regions: &[RegionOutput]) -> String {
+    let mut csv = String::from(
+        "region,day,susceptible,exposed,infectious,removed,reproduction_number,deaths,vaccinated,hospitalized,icu,exposed_variant,infectious_variant\n",
+    );
+    for region in regions {
+        for (day, state) in region.trajectory.iter().enumerate() {
+            let values = state
+                .iter()
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            // Writing to a String can't fail
+            let _ = writeln!(csv, "{},{},{}", region.region, day, values);
+        }
+    }
+    csv
+}
+
+fn run(options: Options) -> Result<(), Box<dyn Error>> {
+    let start: Start = read_json(&options.level_dir.join("start.json"))?;
+    let control_measure_data: HashMap<String, ControlMeasureParams> =
+        read_json(&options.level_dir.join("control.json"))?;
+    let event_data: HashMap<String, EventParams> =
+        read_json(&options.level_dir.join("event.json"))?;
+
+    let mut regions = start
+        .regions()
+        .map(|(id, config)| Ok(Region::new(id.parse()?, config)))
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    regions.sort_by_key(|region| region.id);
+
+    let mut actions: Vec<Action> = match &options.actions {
+        Some(path) => read_json(path)?,
+        None => Vec::new(),
+    };
+    // Stable, so that actions on the same day are played in the order they were written
+    actions.sort_by_key(Action::day);
+    for action in &actions {
+        play(&mut regions, action, &control_measure_data, &event_data)
+            .map_err(|e| format!("{:?}: {}", action, e))?;
+    }
+
+    let end = options.days as f64;
+    let trajectories = match start.mobility {
+        Some(mobility) => {
+            if mobility.len() != regions.len() {
+                return Err("The mobility matrix must have a row per region".into());
+            }
+            if options.seed.is_some() {
+                eprintln!("Levels with a mobility matrix are always simulated deterministically");
+            }
+            let simulators = regions
+                .iter()
+                .map(Region::simulator)
+                .collect::<Result<Vec<_>, _>>()?;
+            Metapopulation::new(simulators, mobility)?
+                .with_solver(start.solver)
+                .simulate(0.0, end)?
+        }
+        None => regions
+            .iter()
+            .enumerate()
+            .map(|(index, region)| {
+                let simulator = region.simulator()?;
+                Ok(match options.seed {
+                    // Each region gets its own stream, like the server seeds them per region
+                    Some(seed) => TauLeaping::new(options.population, seed ^ index as u64)
+                        .simulate(simulator, 0.0, end)?,
+                    None => Deterministic::new(start.solver).simulate(simulator, 0.0, end)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?,
+    };
+
+    let trajectories_in_people = trajectories
+        .iter()
+        .map(|trajectory| in_people(trajectory, options.population))
+        .collect::<Vec<_>>();
+    let outputs = regions
+        .iter()
+        .zip(&trajectories)
+        .zip(&trajectories_in_people)
+        .map(|((region, trajectory), in_people)| RegionOutput {
+            region: region.id,
+            summary: summary_in_people(
+                Summary::new(trajectory, &region.scheduled_config(), options.threshold),
+                options.population,
+            ),
+            trajectory: in_people,
+        })
+        .collect::<Vec<_>>();
+
+    println!(
+        "{:>6} {:>8} {:>15} {:>12} {:>10} {:>12} {:>12}",
+        "region", "peak_day", "peak_infections", "final_size", "deaths", "days_above", "herd_day"
+    );
+    for output in &outputs {
+        let summary = &output.summary;
+        println!(
+            "{:>6} {:>8} {:>15.1} {:>12.1} {:>10.1} {:>12} {:>12}",
+            output.region,
+            summary.peak_day,
+            summary.peak_infections,
+            summary.final_size,
+            summary.deaths,
+            summary.days_above_threshold,
+            summary
+                .herd_immunity_day
+                .map_or_else(|| "-".to_string(), |day| day.to_string()),
+        );
+    }
+
+    if let Some(path) = &options.output {
+        let contents = match options.format() {
+            Format::Csv => write_csv(&outputs),
+            Format::Json => serde_json::to_string(&outputs)?,
+        };
+        fs::write(path, contents)
+            .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(options) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,379 @@
+use crate::{Schedule, Seasonality, State, HOSPITALIZED, ICU};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Starting point of a simulation. Compartments are fractions of the population.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InitialState {
+    pub susceptible: f64,
+    pub exposed: f64,
+    pub infectious: f64,
+    pub removed: f64,
+    #[serde(default)]
+    pub deaths: f64,
+    #[serde(default)]
+    pub vaccinated: f64,
+    #[serde(default)]
+    pub hospitalized: f64,
+    #[serde(default)]
+    pub icu: f64,
+    pub current_reproduction_number: f64,
+}
+
+/// Rates driving the model. All rates are per day.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Parameters {
+    /// Reproduction number the current one relaxes towards
+    pub ideal_reproduction_number: f64,
+    /// How fast the current reproduction number follows the ideal one
+    pub compliance_factor: f64,
+    /// Rate at which people leave I
+    pub recovery_rate: f64,
+    /// Rate at which people move from E to I
+    pub infection_rate: f64,
+    /// Fraction of people leaving I who die
+    #[serde(default)]
+    pub fatality_rate: f64,
+    /// Fraction of S vaccinated per day
+    #[serde(default)]
+    pub vaccination_rate: f64,
+    /// Fraction of infections a vaccine prevents
+    #[serde(default)]
+    pub vaccine_efficacy: f64,
+    /// Scales the coupling of a region to the others, only used by
+    /// [`crate::metapopulation::Metapopulation`]
+    #[serde(default = "full_mobility")]
+    pub mobility_factor: f64,
+    /// Fraction of people leaving I who need a hospital bed
+    #[serde(default)]
+    pub hospitalization_rate: f64,
+    /// Fraction of people leaving the hospital who move on to the ICU
+    #[serde(default)]
+    pub icu_rate: f64,
+    /// Rate at which people leave the hospital
+    #[serde(default)]
+    pub hospital_stay_rate: f64,
+    /// Rate at which people leave the ICU
+    #[serde(default)]
+    pub icu_stay_rate: f64,
+    /// Fraction of people leaving the ICU who die, while there are enough beds
+    #[serde(default)]
+    pub icu_fatality_rate: f64,
+    /// Fraction of patients left without a bed who die
+    #[serde(default)]
+    pub overflow_fatality_rate: f64,
+    /// Hospital beds, as a fraction of the population
+    #[serde(default = "full_capacity")]
+    pub hospital_capacity: f64,
+    /// ICU beds, as a fraction of the population
+    #[serde(default = "full_capacity")]
+    pub icu_capacity: f64,
+    /// Rate at which recovered people lose their immunity and move from R back to S. Zero
+    /// keeps immunity for good.
+    #[serde(default)]
+    pub waning_rate: f64,
+    /// Fraction of infections that are tested and become confirmed cases
+    #[serde(default)]
+    pub testing_rate: f64,
+    /// Days between an infection and its confirmation being reported
+    #[serde(default)]
+    pub reporting_delay: f64,
+    /// Second strain spreading alongside the original one, once an event brought it in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<Variant>,
+}
+
+/// A second strain of the virus. The people it infects go through their own E and I
+/// compartments and then share the hospitals and R with everyone else, so recovering from either
+/// strain protects against the original one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Variant {
+    /// Reproduction number of the variant relative to the original strain's
+    pub transmissibility: f64,
+    /// Fraction of the immunity of recovered and vaccinated people the variant gets around
+    #[serde(default)]
+    pub immunity_escape: f64,
+    /// Fraction of S infected with the variant from abroad per day, which seeds it
+    #[serde(default = "default_importation_rate")]
+    pub importation_rate: f64,
+}
+
+/// About one person in a hundred thousand a day
+fn default_importation_rate() -> f64 {
+    1.0e-5
+}
+
+impl Variant {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check_range("transmissibility", self.transmissibility, 0.0, 5.0)?;
+        check_range("immunity_escape", self.immunity_escape, 0.0, 1.0)?;
+        check_range("importation_rate", self.importation_rate, 0.0, 0.01)
+    }
+}
+
+fn full_mobility() -> f64 {
+    1.0
+}
+
+/// A bed for everyone, so that levels without hospitals are never overwhelmed
+fn full_capacity() -> f64 {
+    1.0
+}
+
+/// Everything a [`crate::Simulator`] needs.
+///
+/// Serializes to a flat object, the same shape as a region entry of a level's `start.json`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SimulatorConfig {
+    #[serde(flatten)]
+    pub initial_state: InitialState,
+    #[serde(flatten)]
+    pub parameters: Parameters,
+    /// Changes to the parameters over the course of the simulation
+    #[serde(default, skip_serializing_if = "Schedule::is_empty")]
+    pub schedule: Schedule,
+    #[serde(default, skip_serializing_if = "Seasonality::is_none")]
+    pub seasonality: Seasonality,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ConfigError {
+    #[error("{field} is required")]
+    Missing { field: &'static str },
+    #[error("{field} must be between {min} and {max}, got {value}")]
+    OutOfRange {
+        field: &'static str,
+        value: f64,
+        min: f64,
+        max: f64,
+    },
+    #[error("Compartments add up to {total} of the population")]
+    PopulationExceeded { total: f64 },
+    #[error("Mobility matrix must have {regions} rows of {regions} entries, one per region")]
+    MobilityShape { regions: usize },
+}
+
+/// Slack allowed when the compartments are checked to add up to at most the whole population
+const POPULATION_TOLERANCE: f64 = 1.0e-6;
+
+pub(crate) fn check_range(
+    field: &'static str,
+    value: f64,
+    min: f64,
+    max: f64,
+) -> Result<(), ConfigError> {
+    // Written so that NaN fails the check too
+    if value >= min && value <= max {
+        Ok(())
+    } else {
+        Err(ConfigError::OutOfRange {
+            field,
+            value,
+            min,
+            max,
+        })
+    }
+}
+
+impl SimulatorConfig {
+    pub fn builder() -> SimulatorConfigBuilder {
+        SimulatorConfigBuilder::default()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let state = &self.initial_state;
+        let compartments = [
+            ("susceptible", state.susceptible),
+            ("exposed", state.exposed),
+            ("infectious", state.infectious),
+            ("removed", state.removed),
+            ("deaths", state.deaths),
+            ("vaccinated", state.vaccinated),
+            ("hospitalized", state.hospitalized),
+            ("icu", state.icu),
+        ];
+        for (field, value) in compartments {
+            check_range(field, value, 0.0, 1.0)?;
+        }
+        let total: f64 = compartments.iter().map(|(_, value)| value).sum();
+        if total > 1.0 + POPULATION_TOLERANCE {
+            return Err(ConfigError::PopulationExceeded { total });
+        }
+
+        check_range(
+            "current_reproduction_number",
+            state.current_reproduction_number,
+            0.0,
+            20.0,
+        )?;
+        self.parameters.validate()?;
+        self.schedule.validate()?;
+        self.seasonality.validate()
+    }
+}
+
+impl Parameters {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check_range(
+            "ideal_reproduction_number",
+            self.ideal_reproduction_number,
+            0.0,
+            20.0,
+        )?;
+        check_range("compliance_factor", self.compliance_factor, 0.0, 1.0)?;
+        check_range("recovery_rate", self.recovery_rate, 0.0, 1.0)?;
+        check_range("infection_rate", self.infection_rate, 0.0, 1.0)?;
+        check_range("fatality_rate", self.fatality_rate, 0.0, 1.0)?;
+        check_range("vaccination_rate", self.vaccination_rate, 0.0, 1.0)?;
+        check_range("vaccine_efficacy", self.vaccine_efficacy, 0.0, 1.0)?;
+        check_range("mobility_factor", self.mobility_factor, 0.0, 1.0)?;
+        check_range("hospitalization_rate", self.hospitalization_rate, 0.0, 1.0)?;
+        check_range("icu_rate", self.icu_rate, 0.0, 1.0)?;
+        check_range("hospital_stay_rate", self.hospital_stay_rate, 0.0, 1.0)?;
+        check_range("icu_stay_rate", self.icu_stay_rate, 0.0, 1.0)?;
+        check_range("icu_fatality_rate", self.icu_fatality_rate, 0.0, 1.0)?;
+        check_range(
+            "overflow_fatality_rate",
+            self.overflow_fatality_rate,
+            0.0,
+            1.0,
+        )?;
+        check_range("hospital_capacity", self.hospital_capacity, 0.0, 1.0)?;
+        check_range("icu_capacity", self.icu_capacity, 0.0, 1.0)?;
+        check_range("waning_rate", self.waning_rate, 0.0, 1.0)?;
+        check_range("testing_rate", self.testing_rate, 0.0, 1.0)?;
+        check_range("reporting_delay", self.reporting_delay, 0.0, 60.0)?;
+        self.variant.as_ref().map_or(Ok(()), Variant::validate)
+    }
+
+    /// Whether the hospital or ICU patients of `state` exceed the beds
+    pub fn hospitals_overwhelmed(&self, state: &State) -> bool {
+        state[HOSPITALIZED] > self.hospital_capacity || state[ICU] > self.icu_capacity
+    }
+}
+
+macro_rules! builder_fields {
+    ($($field:ident),* $(,)?) => {
+        /// Builds a [`SimulatorConfig`] field by field, so that values can't be mixed up by position
+        #[derive(Default, Clone, Debug)]
+        pub struct SimulatorConfigBuilder {
+            $($field: Option<f64>,)*
+            schedule: Schedule,
+            seasonality: Seasonality,
+            variant: Option<Variant>,
+        }
+
+        impl SimulatorConfigBuilder {
+            $(
+                pub fn $field(mut self, value: f64) -> Self {
+                    self.$field = Some(value);
+                    self
+                }
+            )*
+        }
+    };
+}
+
+builder_fields!(
+    susceptible,
+    exposed,
+    infectious,
+    removed,
+    deaths,
+    vaccinated,
+    hospitalized,
+    icu,
+    current_reproduction_number,
+    ideal_reproduction_number,
+    compliance_factor,
+    recovery_rate,
+    infection_rate,
+    fatality_rate,
+    vaccination_rate,
+    vaccine_efficacy,
+    mobility_factor,
+    hospitalization_rate,
+    icu_rate,
+    hospital_stay_rate,
+    icu_stay_rate,
+    icu_fatality_rate,
+    overflow_fatality_rate,
+    hospital_capacity,
+    icu_capacity,
+    waning_rate,
+    testing_rate,
+    reporting_delay,
+);
+
+fn required(field: &'static str, value: Option<f64>) -> Result<f64, ConfigError> {
+    value.ok_or(ConfigError::Missing { field })
+}
+
+impl SimulatorConfigBuilder {
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    pub fn seasonality(mut self, seasonality: Seasonality) -> Self {
+        self.seasonality = seasonality;
+        self
+    }
+
+    pub fn variant(mut self, variant: Variant) -> Self {
+        self.variant = Some(variant);
+        self
+    }
+
+    /// Deaths, vaccinations, hospitalizations, waning, testing and the rates driving them
+    /// default to zero, the mobility factor and bed capacities to one, the schedule to no
+    /// changes, transmission to no seasonality and there is no variant. Everything else is
+    /// required.
+    pub fn build(self) -> Result<SimulatorConfig, ConfigError> {
+        let config = SimulatorConfig {
+            initial_state: InitialState {
+                susceptible: required("susceptible", self.susceptible)?,
+                exposed: required("exposed", self.exposed)?,
+                infectious: required("infectious", self.infectious)?,
+                removed: required("removed", self.removed)?,
+                deaths: self.deaths.unwrap_or_default(),
+                vaccinated: self.vaccinated.unwrap_or_default(),
+                hospitalized: self.hospitalized.unwrap_or_default(),
+                icu: self.icu.unwrap_or_default(),
+                current_reproduction_number: required(
+                    "current_reproduction_number",
+                    self.current_reproduction_number,
+                )?,
+            },
+            parameters: Parameters {
+                ideal_reproduction_number: required(
+                    "ideal_reproduction_number",
+                    self.ideal_reproduction_number,
+                )?,
+                compliance_factor: required("compliance_factor", self.compliance_factor)?,
+                recovery_rate: required("recovery_rate", self.recovery_rate)?,
+                infection_rate: required("infection_rate", self.infection_rate)?,
+                fatality_rate: self.fatality_rate.unwrap_or_default(),
+                vaccination_rate: self.vaccination_rate.unwrap_or_default(),
+                vaccine_efficacy: self.vaccine_efficacy.unwrap_or_default(),
+                mobility_factor: self.mobility_factor.unwrap_or_else(full_mobility),
+                hospitalization_rate: self.hospitalization_rate.unwrap_or_default(),
+                icu_rate: self.icu_rate.unwrap_or_default(),
+                hospital_stay_rate: self.hospital_stay_rate.unwrap_or_default(),
+                icu_stay_rate: self.icu_stay_rate.unwrap_or_default(),
+                icu_fatality_rate: self.icu_fatality_rate.unwrap_or_default(),
+                overflow_fatality_rate: self.overflow_fatality_rate.unwrap_or_default(),
+                hospital_capacity: self.hospital_capacity.unwrap_or_else(full_capacity),
+                icu_capacity: self.icu_capacity.unwrap_or_else(full_capacity),
+                waning_rate: self.waning_rate.unwrap_or_default(),
+                testing_rate: self.testing_rate.unwrap_or_default(),
+                reporting_delay: self.reporting_delay.unwrap_or_default(),
+                variant: self.variant,
+            },
+            schedule: self.schedule,
+            seasonality: self.seasonality,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
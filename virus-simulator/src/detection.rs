@@ -0,0 +1,68 @@
+//! Cases as health authorities see them. Only a fraction of infections is ever confirmed, by
+//! tests whose results come in days after the infection, so the observed curve lags behind and
+//! undercounts the true one.
+//!
+//! Cases are derived from a trajectory after the fact, whichever backend produced it. Like the
+//! trajectory, they are fractions of the population on each day counted from its first state.
+use crate::{Parameters, Schedule, State, EXPOSED, EXPOSED_VARIANT};
+use serde::Serialize;
+
+/// New cases on each day of a trajectory
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Cases {
+    /// People infected on each day, tested or not
+    pub true_cases: Vec<f64>,
+    /// Confirmed cases reported on each day
+    pub observed_cases: Vec<f64>,
+}
+
+/// New infections on each day, the first day having none. `parameters` gives the parameters
+/// in use on a day.
+pub fn infections<'a>(
+    trajectory: &[State],
+    parameters: impl Fn(usize) -> &'a Parameters,
+) -> Vec<f64> {
+    let mut infections = Vec::with_capacity(trajectory.len());
+    if !trajectory.is_empty() {
+        infections.push(0.0);
+    }
+    for (day, pair) in trajectory.windows(2).enumerate() {
+        let (before, after) = (&pair[0], &pair[1]);
+        // Every infection, whichever the strain or where the person came from, goes through
+        // an exposed compartment, which people leave at the infection rate. This is taken over
+        // the day's average of the compartments.
+        let exposed = |state: &State| state[EXPOSED] + state[EXPOSED_VARIANT];
+        let gained = exposed(after) - exposed(before);
+        let left = parameters(day).infection_rate * (exposed(before) + exposed(after)) / 2.0;
+        infections.push((gained + left).max(0.0));
+    }
+    infections
+}
+
+/// True and observed cases of a trajectory simulated from `start_parameters` and following
+/// `schedule`. Infections are confirmed with the testing rate of the day they happened and
+/// reported with the delay of that day, spread over the two closest days if it isn't whole.
+pub fn cases(trajectory: &[State], start_parameters: &Parameters, schedule: &Schedule) -> Cases {
+    let parameters = |day: usize| schedule.parameters_at(start_parameters, day as f64);
+    let true_cases = infections(trajectory, parameters);
+
+    let mut observed_cases = vec![0.0; true_cases.len()];
+    for (day, &infections) in true_cases.iter().enumerate() {
+        let parameters = parameters(day);
+        let confirmed = infections * parameters.testing_rate;
+        let reported = day as f64 + parameters.reporting_delay;
+        let first = reported.floor() as usize;
+        let share_of_next = reported - reported.floor();
+        if let Some(cases) = observed_cases.get_mut(first) {
+            *cases += confirmed * (1.0 - share_of_next);
+        }
+        if let Some(cases) = observed_cases.get_mut(first + 1) {
+            *cases += confirmed * share_of_next;
+        }
+    }
+
+    Cases {
+        true_cases,
+        observed_cases,
+    }
+}
@@ -0,0 +1,406 @@
+//! Runs a simulation many times with sampled parameters and summarizes the spread of the
+//! results as percentile bands.
+//!
+//! Every run gets its own random number generator, seeded from the ensemble's seed and the
+//! index of the run, so that the bands don't depend on how the runs are spread over threads.
+use crate::{
+    Backend, Parameters, Schedule, SimulationError, Simulator, SimulatorConfig, State, STATE_SIZE,
+};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution as _, LogNormal, Normal, Uniform};
+use serde::{Deserialize, Serialize};
+use std::thread;
+
+/// Distribution of the factor a parameter is multiplied by
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Distribution {
+    Uniform {
+        min: f64,
+        max: f64,
+    },
+    Normal {
+        mean: f64,
+        std_dev: f64,
+    },
+    /// The logarithm of the factor is normal with mean 0, e.g. a `sigma` of 0.1 is about ±10%
+    LogNormal {
+        sigma: f64,
+    },
+}
+
+impl Distribution {
+    /// Invalid distributions, like a negative standard deviation, always give 1
+    fn sample(&self, rng: &mut StdRng) -> f64 {
+        let factor = match *self {
+            Distribution::Uniform { min, max } if min < max => Uniform::new(min, max).sample(rng),
+            Distribution::Normal { mean, std_dev } => {
+                Normal::new(mean, std_dev).map_or(1.0, |normal| normal.sample(rng))
+            }
+            Distribution::LogNormal { sigma } => {
+                LogNormal::new(0.0, sigma).map_or(1.0, |log_normal| log_normal.sample(rng))
+            }
+            _ => 1.0,
+        };
+        factor.max(0.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SampledParameter {
+    IdealReproductionNumber,
+    ComplianceFactor,
+    RecoveryRate,
+    InfectionRate,
+    FatalityRate,
+    VaccineEfficacy,
+    HospitalizationRate,
+    WaningRate,
+}
+
+impl SampledParameter {
+    /// The value in `parameters` and the largest one [`Parameters::validate`] accepts
+    fn value<'a>(&self, parameters: &'a mut Parameters) -> (&'a mut f64, f64) {
+        match self {
+            SampledParameter::IdealReproductionNumber => {
+                (&mut parameters.ideal_reproduction_number, 20.0)
+            }
+            SampledParameter::ComplianceFactor => (&mut parameters.compliance_factor, 1.0),
+            SampledParameter::RecoveryRate => (&mut parameters.recovery_rate, 1.0),
+            SampledParameter::InfectionRate => (&mut parameters.infection_rate, 1.0),
+            SampledParameter::FatalityRate => (&mut parameters.fatality_rate, 1.0),
+            SampledParameter::VaccineEfficacy => (&mut parameters.vaccine_efficacy, 1.0),
+            SampledParameter::HospitalizationRate => (&mut parameters.hospitalization_rate, 1.0),
+            SampledParameter::WaningRate => (&mut parameters.waning_rate, 1.0),
+        }
+    }
+}
+
+/// A parameter whose value is uncertain, it is multiplied by a factor drawn for every run
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Perturbation {
+    pub parameter: SampledParameter,
+    pub factor: Distribution,
+}
+
+/// Factors drawn for a run, in the order of the perturbations they were drawn for
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample(Vec<(SampledParameter, f64)>);
+
+impl Sample {
+    pub fn draw(perturbations: &[Perturbation], rng: &mut StdRng) -> Self {
+        Sample(
+            perturbations
+                .iter()
+                .map(|perturbation| (perturbation.parameter, perturbation.factor.sample(rng)))
+                .collect(),
+        )
+    }
+
+    fn apply_to(&self, parameters: &Parameters) -> Parameters {
+        let mut parameters = parameters.clone();
+        for (parameter, factor) in &self.0 {
+            let (value, max) = parameter.value(&mut parameters);
+            *value = (*value * factor).min(max);
+        }
+        parameters
+    }
+
+    /// Scales the parameters of `config`, both before and after each change of its schedule
+    pub fn apply(&self, config: &SimulatorConfig) -> SimulatorConfig {
+        self.apply_from(config, 0)
+    }
+
+    /// Scales the parameters `config` uses from `day` on, so that runs only diverge from then
+    pub fn apply_from(&self, config: &SimulatorConfig, day: u32) -> SimulatorConfig {
+        let mut schedule = Schedule::new();
+        for change in config.schedule.changes() {
+            let parameters = if change.day >= day {
+                self.apply_to(&change.parameters)
+            } else {
+                change.parameters.clone()
+            };
+            schedule.insert(change.day, parameters);
+        }
+        let parameters = if day == 0 {
+            self.apply_to(&config.parameters)
+        } else {
+            if !config
+                .schedule
+                .changes()
+                .iter()
+                .any(|change| change.day == day)
+            {
+                let current = config
+                    .schedule
+                    .parameters_at(&config.parameters, day as f64);
+                schedule.insert(day, self.apply_to(current));
+            }
+            config.parameters.clone()
+        };
+        SimulatorConfig {
+            initial_state: config.initial_state.clone(),
+            parameters,
+            schedule,
+            seasonality: config.seasonality.clone(),
+        }
+    }
+}
+
+/// Percentiles of every compartment on every day across the runs of an ensemble
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bands {
+    /// In increasing order, between 0 and 100
+    pub percentiles: Vec<f64>,
+    /// One trajectory per percentile. Each value is a percentile of its compartment on its
+    /// day taken on its own, so a band isn't the trajectory of any single run.
+    pub trajectories: Vec<Vec<State>>,
+}
+
+impl Bands {
+    pub fn percentile(&self, percentile: f64) -> Option<&[State]> {
+        self.percentiles
+            .iter()
+            .position(|&p| p == percentile)
+            .map(|index| self.trajectories[index].as_slice())
+    }
+
+    pub fn median(&self) -> Option<&[State]> {
+        self.percentile(50.0)
+    }
+}
+
+/// Settings of a Monte Carlo ensemble
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ensemble {
+    runs: usize,
+    seed: u64,
+    threads: usize,
+    percentiles: Vec<f64>,
+}
+
+impl Ensemble {
+    /// Runs on every available core and gives the median with 50% and 90% bands
+    pub fn new(runs: usize) -> Self {
+        Self {
+            runs: runs.max(1),
+            seed: 0,
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+            percentiles: vec![5.0, 25.0, 50.0, 75.0, 95.0],
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn with_percentiles(mut self, percentiles: &[f64]) -> Self {
+        let mut percentiles: Vec<f64> = percentiles
+            .iter()
+            .map(|percentile| percentile.clamp(0.0, 100.0))
+            .collect();
+        percentiles.sort_by(f64::total_cmp);
+        percentiles.dedup();
+        self.percentiles = percentiles;
+        self
+    }
+
+    /// Calls `simulate` once per run, with the generator of the run, and gathers the
+    /// trajectories into bands. Fails if any of the runs does.
+    pub fn run<F>(&self, simulate: F) -> Result<Bands, SimulationError>
+    where
+        F: Fn(&mut StdRng) -> Result<Vec<State>, SimulationError> + Sync,
+    {
+        let threads = self.threads.min(self.runs);
+        let simulate = &simulate;
+        let mut trajectories = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|worker| {
+                    scope.spawn(move || {
+                        (worker..self.runs)
+                            .step_by(threads)
+                            .map(|run| {
+                                let mut rng =
+                                    StdRng::seed_from_u64(self.seed.wrapping_add(run as u64));
+                                simulate(&mut rng).map(|trajectory| (run, trajectory))
+                            })
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                })
+                .collect();
+            let mut trajectories = Vec::with_capacity(self.runs);
+            for worker in workers {
+                match worker.join() {
+                    Ok(runs) => trajectories.extend(runs?),
+                    // Panics of the simulation are passed on as they are
+                    Err(panic) => std::panic::resume_unwind(panic),
+                }
+            }
+            Ok::<_, SimulationError>(trajectories)
+        })?;
+
+        trajectories.sort_by_key(|(run, _)| *run);
+        let trajectories: Vec<Vec<State>> = trajectories
+            .into_iter()
+            .map(|(_, trajectory)| trajectory)
+            .collect();
+        Ok(self.bands(&trajectories))
+    }
+
+    /// Runs `config` from `start_time` to `end_time` with `backend`, scaling its parameters by
+    /// factors drawn from `perturbations` for every run
+    pub fn simulate<B>(
+        &self,
+        config: &SimulatorConfig,
+        perturbations: &[Perturbation],
+        backend: B,
+        start_time: f64,
+        end_time: f64,
+    ) -> Result<Bands, SimulationError>
+    where
+        B: Fn(&mut StdRng) -> Box<dyn Backend> + Sync,
+    {
+        self.run(|rng| {
+            let sample = Sample::draw(perturbations, rng);
+            let simulator = Simulator::new(sample.apply(config))?;
+            backend(rng).simulate(simulator, start_time, end_time)
+        })
+    }
+
+    fn bands(&self, trajectories: &[Vec<State>]) -> Bands {
+        let days = trajectories.iter().map(Vec::len).min().unwrap_or(0);
+        let mut bands = vec![Vec::with_capacity(days); self.percentiles.len()];
+        let mut values = Vec::with_capacity(trajectories.len());
+        for day in 0..days {
+            let mut states = vec![State::zeros(); self.percentiles.len()];
+            for compartment in 0..STATE_SIZE {
+                values.clear();
+                values.extend(
+                    trajectories
+                        .iter()
+                        .map(|trajectory| trajectory[day][compartment]),
+                );
+                values.sort_by(f64::total_cmp);
+                for (state, &percentile) in states.iter_mut().zip(&self.percentiles) {
+                    state[compartment] = interpolate(&values, percentile);
+                }
+            }
+            for (band, state) in bands.iter_mut().zip(states) {
+                band.push(state);
+            }
+        }
+        Bands {
+            percentiles: self.percentiles.clone(),
+            trajectories: bands,
+        }
+    }
+}
+
+/// Percentile of sorted values, interpolating linearly between the closest ranks
+fn interpolate(sorted: &[f64], percentile: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = percentile / 100.0 * (sorted.len() - 1) as f64;
+    let below = rank.floor() as usize;
+    let above = rank.ceil() as usize;
+    sorted[below] + (rank - below as f64) * (sorted[above] - sorted[below])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    /// Trajectories of `days` days with every compartment at a single value drawn by the run
+    fn constant_run(rng: &mut StdRng, days: usize) -> Result<Vec<State>, SimulationError> {
+        Ok(vec![State::repeat(rng.gen::<f64>()); days])
+    }
+
+    #[test]
+    fn interpolate_between_the_closest_ranks() {
+        let sorted = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(interpolate(&sorted, 0.0), 1.0);
+        assert_eq!(interpolate(&sorted, 25.0), 2.0);
+        assert_eq!(interpolate(&sorted, 50.0), 3.0);
+        assert_eq!(interpolate(&sorted, 62.5), 3.5);
+        assert_eq!(interpolate(&sorted, 100.0), 5.0);
+        assert_eq!(interpolate(&[], 50.0), 0.0);
+    }
+
+    #[test]
+    fn bands_are_percentiles_of_the_runs() {
+        let ensemble = Ensemble::new(101).with_seed(7);
+        let bands = ensemble.run(|rng| constant_run(rng, 3)).unwrap();
+
+        let mut values: Vec<f64> = (0..101)
+            .map(|run| StdRng::seed_from_u64(7 + run).gen::<f64>())
+            .collect();
+        values.sort_by(f64::total_cmp);
+        for (&percentile, trajectory) in bands.percentiles.iter().zip(&bands.trajectories) {
+            assert_eq!(trajectory.len(), 3);
+            // With 101 runs every percentile used is one of the values
+            let expected = values[percentile as usize];
+            assert!(trajectory.iter().flatten().all(|&value| value == expected));
+        }
+        assert!(bands.median().is_some());
+        assert!(bands.percentile(10.0).is_none());
+    }
+
+    #[test]
+    fn bands_dont_depend_on_threads() {
+        let ensemble = Ensemble::new(40)
+            .with_seed(3)
+            .with_percentiles(&[10.0, 50.0, 90.0]);
+        let bands = ensemble
+            .clone()
+            .with_threads(1)
+            .run(|rng| constant_run(rng, 5))
+            .unwrap();
+        assert_eq!(
+            ensemble
+                .with_threads(4)
+                .run(|rng| constant_run(rng, 5))
+                .unwrap(),
+            bands
+        );
+    }
+
+    #[test]
+    fn bands_are_as_long_as_the_shortest_run() {
+        let bands = Ensemble::new(10)
+            .run(|rng| {
+                let days = rng.gen_range(3..8);
+                constant_run(rng, days)
+            })
+            .unwrap();
+        let shortest = (0..10)
+            .map(|run| StdRng::seed_from_u64(run).gen_range(3..8))
+            .min()
+            .unwrap();
+        assert!(bands
+            .trajectories
+            .iter()
+            .all(|trajectory| trajectory.len() == shortest));
+    }
+
+    #[test]
+    fn failing_run_fails_the_ensemble() {
+        let result = Ensemble::new(10).with_threads(2).run(|rng| {
+            if rng.gen::<f64>() < 0.5 {
+                Err(SimulationError::NonFinite { time: 1.0 })
+            } else {
+                constant_run(rng, 2)
+            }
+        });
+        assert!(matches!(result, Err(SimulationError::NonFinite { .. })));
+    }
+}
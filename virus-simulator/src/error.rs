@@ -0,0 +1,47 @@
+use crate::ConfigError;
+use ode_solvers::dop_shared::IntegrationError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SimulationError {
+    #[error("Invalid initial state: {0}")]
+    InvalidInitialState(#[from] ConfigError),
+    #[error("Step size underflow at day {time}")]
+    StepSizeUnderflow { time: f64 },
+    #[error("Needed more than {steps} steps, stopped at day {time}")]
+    TooManySteps { time: f64, steps: u32 },
+    #[error("The system became stiff at day {time}")]
+    Stiff { time: f64 },
+    #[error("State stopped being finite at day {time}")]
+    NonFinite { time: f64 },
+}
+
+impl From<IntegrationError> for SimulationError {
+    fn from(error: IntegrationError) -> Self {
+        match error {
+            IntegrationError::StepSizeUnderflow { x } => {
+                SimulationError::StepSizeUnderflow { time: x }
+            }
+            IntegrationError::MaxNumStepReached { x, n_step } => SimulationError::TooManySteps {
+                time: x,
+                steps: n_step,
+            },
+            IntegrationError::StiffnessDetected { x } => SimulationError::Stiff { time: x },
+        }
+    }
+}
+
+/// Fails on the first output that isn't finite, e.g. when the params blew the state up.
+/// `trajectory` is expected to hold one state per day starting from `start_time`.
+pub(crate) fn check_finite<S>(
+    trajectory: &[S],
+    start_time: f64,
+    is_finite: impl Fn(&S) -> bool,
+) -> Result<(), SimulationError> {
+    match trajectory.iter().position(|state| !is_finite(state)) {
+        Some(day) => Err(SimulationError::NonFinite {
+            time: start_time + day as f64,
+        }),
+        None => Ok(()),
+    }
+}
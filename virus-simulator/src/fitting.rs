@@ -0,0 +1,370 @@
+//! Estimates the parameters of a region from an observed outbreak, for levels following real
+//! curves.
+//!
+//! The ideal reproduction number, infection rate, recovery rate and initial exposed fraction
+//! are fitted by least squares with the Nelder-Mead method. Observed infectious people are
+//! compared to I, H and ICU together and observed removed people to R and D together.
+use crate::level::PARAM_LIMITS;
+use crate::{
+    Backend, ConfigError, Deterministic, InitialState, Parameters, Simulator, SimulatorConfig,
+    State, DEATHS, HOSPITALIZED, ICU, INFECTIOUS, INFECTIOUS_VARIANT, REMOVED,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// People counted on a day
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Observation {
+    pub day: u32,
+    pub infectious: f64,
+    pub removed: f64,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum FitError {
+    #[error("At least {MIN_OBSERVATIONS} observations are needed, got {0}")]
+    TooFewObservations(usize),
+    #[error("Observations must be on increasing days, day {0} isn't")]
+    Unordered(u32),
+    #[error("Population must be positive, got {0}")]
+    InvalidPopulation(f64),
+    #[error("First observation doesn't fit in the population: {0}")]
+    InvalidInitialState(#[from] ConfigError),
+}
+
+/// One per fitted value, so that every value is constrained by more than one point
+const MIN_OBSERVATIONS: usize = 4;
+
+/// Ranges searched for the ideal reproduction number, infection rate, recovery rate and
+/// initial exposed fraction, in that order. The rates stay within the [`PARAM_LIMITS`] players
+/// move them in, so that the first control measure doesn't clamp a fitted region.
+const BOUNDS: [(f64, f64); 4] = [
+    PARAM_LIMITS[0],
+    PARAM_LIMITS[3],
+    PARAM_LIMITS[2],
+    (0.0, 0.05),
+];
+
+const MAX_ITERATIONS: usize = 2000;
+/// Searches stop once the objective at every vertex of the simplex is this close
+const TOLERANCE: f64 = 1.0e-14;
+
+/// Result of [`fit`]
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Fit {
+    /// Ready to be used as a region of a level's `start.json`. Day 0 is the day of the first
+    /// observation.
+    pub config: SimulatorConfig,
+    /// Root mean square difference between the fitted and observed counts, in people
+    pub residual: f64,
+}
+
+/// Fits a region to `observations` of a population of `population` people. The rates that
+/// aren't fitted, like the fatality rate, are taken from `template`.
+///
+/// The region starts with the counts of the first observation and its reproduction number
+/// already at the ideal one.
+pub fn fit(
+    observations: &[Observation],
+    population: f64,
+    template: &Parameters,
+) -> Result<Fit, FitError> {
+    if observations.len() < MIN_OBSERVATIONS {
+        return Err(FitError::TooFewObservations(observations.len()));
+    }
+    if let Some(pair) = observations
+        .windows(2)
+        .find(|pair| pair[0].day >= pair[1].day)
+    {
+        return Err(FitError::Unordered(pair[1].day));
+    }
+    if population.is_nan() || population <= 0.0 {
+        return Err(FitError::InvalidPopulation(population));
+    }
+
+    let first = observations[0];
+    let infectious = first.infectious / population;
+    let middle = |(min, max): (f64, f64)| (min + max) / 2.0;
+    let guess = [
+        middle(BOUNDS[0]),
+        middle(BOUNDS[1]),
+        middle(BOUNDS[2]),
+        (2.0 * infectious).min(BOUNDS[3].1),
+    ];
+    // Fails early if the first observation alone is already invalid
+    config(infectious, first.removed / population, &guess, template).validate()?;
+
+    let objective = |z: &[f64; 4]| {
+        let values = from_unbounded(z);
+        let config = config(infectious, first.removed / population, &values, template);
+        match simulate(config, observations[observations.len() - 1].day - first.day) {
+            Some(trajectory) => observations
+                .iter()
+                .map(|observation| {
+                    let state = &trajectory[(observation.day - first.day) as usize];
+                    let (model_infectious, model_removed) = observed_compartments(state);
+                    (model_infectious - observation.infectious / population).powi(2)
+                        + (model_removed - observation.removed / population).powi(2)
+                })
+                .sum(),
+            None => f64::INFINITY,
+        }
+    };
+
+    // Restarting from the best point found gets the search out of collapsed simplices
+    let mut best = to_unbounded(&guess);
+    let mut best_value = f64::INFINITY;
+    for _ in 0..2 {
+        let (point, value) = nelder_mead(&objective, best);
+        if value <= best_value {
+            best = point;
+            best_value = value;
+        }
+    }
+
+    let values = from_unbounded(&best);
+    Ok(Fit {
+        config: config(infectious, first.removed / population, &values, template),
+        residual: (best_value / (2 * observations.len()) as f64).sqrt() * population,
+    })
+}
+
+/// The compartments comparable to observed infectious and removed people
+fn observed_compartments(state: &State) -> (f64, f64) {
+    (
+        state[INFECTIOUS] + state[INFECTIOUS_VARIANT] + state[HOSPITALIZED] + state[ICU],
+        state[REMOVED] + state[DEATHS],
+    )
+}
+
+fn config(
+    infectious: f64,
+    removed: f64,
+    values: &[f64; 4],
+    template: &Parameters,
+) -> SimulatorConfig {
+    let [reproduction_number, infection_rate, recovery_rate, exposed] = *values;
+    SimulatorConfig {
+        initial_state: InitialState {
+            susceptible: 1.0 - exposed - infectious - removed,
+            exposed,
+            infectious,
+            removed,
+            deaths: 0.0,
+            vaccinated: 0.0,
+            hospitalized: 0.0,
+            icu: 0.0,
+            current_reproduction_number: reproduction_number,
+        },
+        parameters: Parameters {
+            ideal_reproduction_number: reproduction_number,
+            infection_rate,
+            recovery_rate,
+            ..template.clone()
+        },
+        schedule: Default::default(),
+        seasonality: Default::default(),
+    }
+}
+
+fn simulate(config: SimulatorConfig, days: u32) -> Option<Vec<State>> {
+    let simulator = Simulator::new(config).ok()?;
+    Deterministic::default()
+        .simulate(simulator, 0.0, days as f64)
+        .ok()
+}
+
+/// Maps the whole real line onto each of [`BOUNDS`], so that the search is unconstrained
+fn from_unbounded(z: &[f64; 4]) -> [f64; 4] {
+    let mut values = [0.0; 4];
+    for ((value, z), (min, max)) in values.iter_mut().zip(z).zip(BOUNDS) {
+        *value = min + (max - min) / (1.0 + (-z).exp());
+    }
+    values
+}
+
+fn to_unbounded(values: &[f64; 4]) -> [f64; 4] {
+    let mut z = [0.0; 4];
+    for ((z, value), (min, max)) in z.iter_mut().zip(values).zip(BOUNDS) {
+        // Kept off the bounds themselves, which are at infinity
+        let share = ((value - min) / (max - min)).clamp(1.0e-6, 1.0 - 1.0e-6);
+        *z = (share / (1.0 - share)).ln();
+    }
+    z
+}
+
+/// Minimizes `f` starting from `start`, returning the best point found and its value
+fn nelder_mead(f: &impl Fn(&[f64; 4]) -> f64, start: [f64; 4]) -> ([f64; 4], f64) {
+    const N: usize = 4;
+    let mut simplex = vec![start; N + 1];
+    for (index, vertex) in simplex.iter_mut().skip(1).enumerate() {
+        vertex[index] += 1.0;
+    }
+    let mut values: Vec<f64> = simplex.iter().map(f).collect();
+
+    let towards = |from: &[f64; N], to: &[f64; N], factor: f64| {
+        let mut point = *from;
+        for (point, (from, to)) in point.iter_mut().zip(from.iter().zip(to)) {
+            *point = from + factor * (to - from);
+        }
+        point
+    };
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut order: Vec<usize> = (0..=N).collect();
+        order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+        simplex = order.iter().map(|&index| simplex[index]).collect();
+        values = order.iter().map(|&index| values[index]).collect();
+        if values[N] - values[0] < TOLERANCE {
+            break;
+        }
+
+        let mut centroid = [0.0; N];
+        for vertex in &simplex[..N] {
+            for (centroid, value) in centroid.iter_mut().zip(vertex) {
+                *centroid += value / N as f64;
+            }
+        }
+
+        let reflected = towards(&centroid, &simplex[N], -1.0);
+        let reflected_value = f(&reflected);
+        if reflected_value < values[0] {
+            let expanded = towards(&centroid, &simplex[N], -2.0);
+            let expanded_value = f(&expanded);
+            if expanded_value < reflected_value {
+                simplex[N] = expanded;
+                values[N] = expanded_value;
+            } else {
+                simplex[N] = reflected;
+                values[N] = reflected_value;
+            }
+        } else if reflected_value < values[N - 1] {
+            simplex[N] = reflected;
+            values[N] = reflected_value;
+        } else {
+            let contracted = towards(&centroid, &simplex[N], 0.5);
+            let contracted_value = f(&contracted);
+            if contracted_value < values[N] {
+                simplex[N] = contracted;
+                values[N] = contracted_value;
+            } else {
+                let best = simplex[0];
+                for (vertex, value) in simplex.iter_mut().zip(values.iter_mut()).skip(1) {
+                    *vertex = towards(&best, vertex, 0.5);
+                    *value = f(vertex);
+                }
+            }
+        }
+    }
+
+    let best = (0..=N)
+        .min_by(|&a, &b| values[a].total_cmp(&values[b]))
+        .unwrap_or(0);
+    (simplex[best], values[best])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POPULATION: f64 = 5000.0;
+
+    fn template() -> Parameters {
+        SimulatorConfig::builder()
+            .susceptible(1.0)
+            .exposed(0.0)
+            .infectious(0.0)
+            .removed(0.0)
+            .current_reproduction_number(1.0)
+            .ideal_reproduction_number(1.0)
+            .compliance_factor(1.0)
+            .recovery_rate(0.1)
+            .infection_rate(0.2)
+            .build()
+            .unwrap()
+            .parameters
+    }
+
+    /// Observations every five days of a region simulated from `values`
+    fn synthetic(values: &[f64; 4]) -> Vec<Observation> {
+        let trajectory = simulate(config(0.002, 0.0, values, &template()), 120).unwrap();
+        (0..=120)
+            .step_by(5)
+            .map(|day| {
+                let (infectious, removed) = observed_compartments(&trajectory[day as usize]);
+                Observation {
+                    day,
+                    infectious: infectious * POPULATION,
+                    removed: removed * POPULATION,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recovers_the_parameters_of_a_synthetic_curve() {
+        let values = [1.8, 0.15, 0.08, 0.004];
+        let fitted = fit(&synthetic(&values), POPULATION, &template()).unwrap();
+        let parameters = &fitted.config.parameters;
+        let found = [
+            parameters.ideal_reproduction_number,
+            parameters.infection_rate,
+            parameters.recovery_rate,
+            fitted.config.initial_state.exposed,
+        ];
+        for (found, expected) in found.iter().zip(values) {
+            assert!(
+                (found - expected).abs() < 0.02 * expected,
+                "{:?} isn't {:?}",
+                found,
+                values
+            );
+        }
+        assert!(fitted.residual < 1.0);
+    }
+
+    #[test]
+    fn fits_stay_within_the_limits_players_move_params_in() {
+        // Spreads faster than any level allows
+        let observations = synthetic(&[1.8, 0.15, 0.08, 0.004])
+            .into_iter()
+            .map(|observation| Observation {
+                day: observation.day / 2,
+                ..observation
+            })
+            .collect::<Vec<_>>();
+        let parameters = fit(&observations, POPULATION, &template())
+            .unwrap()
+            .config
+            .parameters;
+        // The compliance factor comes from the template
+        let adjustable = crate::level::adjustable_params(&parameters);
+        for index in [0, 2, 3] {
+            let (min, max) = PARAM_LIMITS[index];
+            assert!(adjustable[index] >= min && adjustable[index] <= max);
+        }
+    }
+
+    #[test]
+    fn bounded_values_survive_the_round_trip() {
+        let values = [2.0, 0.1, 0.07, 0.01];
+        for (found, expected) in from_unbounded(&to_unbounded(&values)).iter().zip(values) {
+            assert!((found - expected).abs() < 1.0e-12);
+        }
+    }
+
+    #[test]
+    fn rejects_too_few_or_unordered_observations() {
+        let observations = synthetic(&[1.8, 0.15, 0.08, 0.004]);
+        assert_eq!(
+            fit(&observations[..3], POPULATION, &template()),
+            Err(FitError::TooFewObservations(3))
+        );
+        let mut unordered = observations;
+        unordered.swap(1, 2);
+        assert_eq!(
+            fit(&unordered, POPULATION, &template()),
+            Err(FitError::Unordered(unordered[2].day))
+        );
+    }
+}
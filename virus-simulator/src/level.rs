@@ -0,0 +1,223 @@
+//! The files a level is made of and the rules turning player actions into parameters, shared by
+//! the game server and the `simulate_level` binary so that both run the same math.
+use crate::{Parameters, Seasonality, SimulatorConfig, Solver, Threshold, Variant};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Bounds of the params players can change through control measures and events: the ideal
+/// reproduction number, compliance factor, recovery rate and infection rate, in that order
+pub const PARAM_LIMITS: &[(f64, f64)] = &[(1.2, 3.0), (0.0, 0.8), (0.05, 0.1), (0.05, 0.30)];
+
+/// `start.json`, regions are keyed by their id starting from 1
+#[derive(Deserialize, Clone, Debug)]
+pub struct Start {
+    /// Configs without a schedule, the level's starting point
+    pub params: HashMap<String, SimulatorConfig>,
+    /// Regions are simulated together, coupled through this matrix, when it is present
+    #[serde(default)]
+    pub mobility: Option<Vec<Vec<f64>>>,
+    /// How the level is integrated when simulated deterministically
+    #[serde(default)]
+    pub solver: Solver,
+    /// Seasonality of every region that doesn't have its own
+    #[serde(default)]
+    pub seasonality: Seasonality,
+    /// Watched in every region
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+}
+
+/// What a region crossing the threshold of a [`Trigger`] means for the player
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    News,
+    Loss,
+    Victory,
+}
+
+/// A threshold of `start.json` ending the level or bringing news partway through it, e.g.
+/// `{ "compartment": 2, "level": 0.1, "direction": "rising", "outcome": "loss", ... }`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Trigger {
+    #[serde(flatten)]
+    pub threshold: Threshold,
+    pub outcome: Outcome,
+    pub headline: String,
+}
+
+impl Start {
+    /// Starting config of a region, with the level's seasonality unless it has its own
+    pub fn region(&self, id: &str) -> Option<SimulatorConfig> {
+        self.params
+            .get(id)
+            .map(|config| self.with_seasonality(config))
+    }
+
+    /// Starting configs of every region with their ids, as given by [`Start::region`]
+    pub fn regions(&self) -> impl Iterator<Item = (&str, SimulatorConfig)> {
+        self.params
+            .iter()
+            .map(|(id, config)| (id.as_str(), self.with_seasonality(config)))
+    }
+
+    fn with_seasonality(&self, config: &SimulatorConfig) -> SimulatorConfig {
+        let mut config = config.clone();
+        if config.seasonality.is_none() {
+            config.seasonality = self.seasonality.clone();
+        }
+        config
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ControlMeasureLevel {
+    pub params_delta: Vec<f64>,
+    pub cost: u32,
+    /// Fraction of the susceptible population vaccinated per day while the measure is active
+    #[serde(default)]
+    pub vaccination_rate: f64,
+    /// Scales the coupling between the region and the others while the measure is active
+    #[serde(default)]
+    pub mobility_factor: Option<f64>,
+    /// Hospital beds added while the measure is active, as a fraction of the population
+    #[serde(default)]
+    pub hospital_capacity: f64,
+    /// ICU beds added while the measure is active, as a fraction of the population
+    #[serde(default)]
+    pub icu_capacity: f64,
+    /// Fraction of infections confirmed by the measure, on top of the level's own testing
+    #[serde(default)]
+    pub testing_rate: f64,
+}
+
+/// A control measure of `control.json`, which is keyed by name
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ControlMeasureParams {
+    pub description: String,
+    pub levels: HashMap<i32, ControlMeasureLevel>,
+    pub mess_up_chance: f32,
+}
+
+/// An event of `event.json`, which is keyed by id
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EventParams {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+    pub params_delta: Vec<f64>,
+    pub region: i32,
+    pub reward: i32,
+    /// Second strain the region is exposed to from then on when the event is accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<Variant>,
+}
+
+/// The params players can change, in the order of [`PARAM_LIMITS`]
+pub fn adjustable_params(parameters: &Parameters) -> Vec<f64> {
+    vec![
+        parameters.ideal_reproduction_number,
+        parameters.compliance_factor,
+        parameters.recovery_rate,
+        parameters.infection_rate,
+    ]
+}
+
+/// Adds `delta` to the params players can change, keeping each of them within [`PARAM_LIMITS`]
+pub fn apply_delta(params: &[f64], delta: &[f64]) -> Vec<f64> {
+    params
+        .iter()
+        .zip(delta)
+        .zip(PARAM_LIMITS)
+        .map(|((param, delta), &(min, max))| (param + delta).max(min).min(max))
+        .collect()
+}
+
+/// Level info of every control measure active in a region
+fn active_levels<'a>(
+    active_control_measures: &'a HashMap<String, i32>,
+    control_measure_data: &'a HashMap<String, ControlMeasureParams>,
+) -> impl Iterator<Item = &'a ControlMeasureLevel> {
+    active_control_measures
+        .iter()
+        .filter_map(|(name, level)| control_measure_data.get(name)?.levels.get(level))
+}
+
+/// Daily vaccination rate resulting from the control measures active in a region
+pub fn vaccination_rate(
+    active_control_measures: &HashMap<String, i32>,
+    control_measure_data: &HashMap<String, ControlMeasureParams>,
+) -> f64 {
+    active_levels(active_control_measures, control_measure_data)
+        .map(|level_info| level_info.vaccination_rate)
+        .sum()
+}
+
+/// Fraction of infections confirmed thanks to the control measures active in a region
+pub fn testing_rate(
+    active_control_measures: &HashMap<String, i32>,
+    control_measure_data: &HashMap<String, ControlMeasureParams>,
+) -> f64 {
+    active_levels(active_control_measures, control_measure_data)
+        .map(|level_info| level_info.testing_rate)
+        .sum()
+}
+
+/// Factor scaling the coupling of a region to the others, from its active control measures
+pub fn mobility_factor(
+    active_control_measures: &HashMap<String, i32>,
+    control_measure_data: &HashMap<String, ControlMeasureParams>,
+) -> f64 {
+    active_levels(active_control_measures, control_measure_data)
+        .filter_map(|level_info| level_info.mobility_factor)
+        .product()
+}
+
+/// Hospital and ICU beds added by the control measures active in a region
+pub fn extra_capacity(
+    active_control_measures: &HashMap<String, i32>,
+    control_measure_data: &HashMap<String, ControlMeasureParams>,
+) -> (f64, f64) {
+    active_levels(active_control_measures, control_measure_data).fold(
+        (0.0, 0.0),
+        |(hospital, icu), level_info| {
+            (
+                hospital + level_info.hospital_capacity,
+                icu + level_info.icu_capacity,
+            )
+        },
+    )
+}
+
+/// Parameters a region switches to after a control measure or event. `changed_params` are the
+/// adjustable params, rates that are a property of the level come from the start params, the
+/// variant from the `current_params` and the rest from the control measures active in the
+/// region.
+pub fn scheduled_parameters(
+    start_params: &Parameters,
+    current_params: &Parameters,
+    changed_params: &[f64],
+    active_control_measures: &HashMap<String, i32>,
+    control_measure_data: &HashMap<String, ControlMeasureParams>,
+) -> Parameters {
+    let (hospital_capacity, icu_capacity) =
+        extra_capacity(active_control_measures, control_measure_data);
+    Parameters {
+        ideal_reproduction_number: changed_params[0],
+        compliance_factor: changed_params[1],
+        recovery_rate: changed_params[2],
+        infection_rate: changed_params[3],
+        vaccination_rate: (start_params.vaccination_rate
+            + vaccination_rate(active_control_measures, control_measure_data))
+        .min(1.0),
+        mobility_factor: mobility_factor(active_control_measures, control_measure_data),
+        hospital_capacity: (start_params.hospital_capacity + hospital_capacity).min(1.0),
+        icu_capacity: (start_params.icu_capacity + icu_capacity).min(1.0),
+        testing_rate: (start_params.testing_rate
+            + testing_rate(active_control_measures, control_measure_data))
+        .min(1.0),
+        // Once a variant is in a region it stays
+        variant: current_params.variant.clone(),
+        ..start_params.clone()
+    }
+}
@@ -1,82 +1,404 @@
 #![crate_name = "virus_simulator"]
-use ode_solvers::dopri5::*;
-use ode_solvers::*;
 
-pub type State = Vector5<f64>;
+pub mod analysis;
+pub mod config;
+pub mod detection;
+pub mod ensemble;
+mod error;
+pub mod fitting;
+pub mod level;
+pub mod metapopulation;
+mod schedule;
+mod seasonality;
+mod solver;
+pub mod stochastic;
+pub mod threshold;
+
+pub use config::{ConfigError, InitialState, Parameters, SimulatorConfig, Variant};
+pub use error::SimulationError;
+pub use schedule::{ParameterChange, Schedule};
+pub use seasonality::Seasonality;
+pub use solver::Solver;
+pub use threshold::{Direction, Threshold};
+
+/// S, E, I, R, current reproduction number, D, V, H (hospitalized), ICU and the E and I of the
+/// variant, in that order.
+///
+/// Compartments added after the reproduction number are appended so that the first five
+/// columns keep the layout clients already rely on. R no longer counts the dead, who are in
+/// their own compartment.
+pub type State = ode_solvers::SVector<f64, STATE_SIZE>;
 type Time = f64;
 
-/// Simulator based on SEIR Model
-pub struct Simulator<'a> {
-    susceptible: &'a f64,
-    exposed: &'a f64,
-    infectious: &'a f64,
-    removed: &'a f64,
-    current_reproduction_number: &'a f64,
-    ideal_reproduction_number: &'a f64,
-    compliance_factor: &'a f64,
-    recovery_rate: &'a f64,
-    infection_rate: &'a f64,
+pub const SUSCEPTIBLE: usize = 0;
+pub const EXPOSED: usize = 1;
+pub const INFECTIOUS: usize = 2;
+/// Recovered, the dead are in [`DEATHS`]
+pub const REMOVED: usize = 3;
+pub const REPRODUCTION_NUMBER: usize = 4;
+pub const DEATHS: usize = 5;
+pub const VACCINATED: usize = 6;
+pub const HOSPITALIZED: usize = 7;
+pub const ICU: usize = 8;
+pub const EXPOSED_VARIANT: usize = 9;
+pub const INFECTIOUS_VARIANT: usize = 10;
+
+/// Number of values in a [`State`]
+pub const STATE_SIZE: usize = 11;
+
+/// `state` counted in people of a region of `population` rather than in fractions of it. The
+/// reproduction number isn't a count and is left as it is.
+pub fn in_people(state: &State, population: f64) -> State {
+    let mut people = state * population;
+    people[REPRODUCTION_NUMBER] = state[REPRODUCTION_NUMBER];
+    people
 }
 
-impl<'a> Simulator<'a> {
-    pub fn new(
-        susceptible: &'a f64,
-        exposed: &'a f64,
-        infectious: &'a f64,
-        removed: &'a f64,
-        current_reproduction_number: &'a f64,
-        ideal_reproduction_number: &'a f64,
-        compliance_factor: &'a f64,
-        recovery_rate: &'a f64,
-        infection_rate: &'a f64,
-    ) -> Self {
-        Self {
-            susceptible,
-            exposed,
-            infectious,
-            removed,
-            current_reproduction_number,
-            ideal_reproduction_number,
-            compliance_factor,
-            recovery_rate,
-            infection_rate,
-        }
+/// Fraction of the patients in a compartment left without a bed
+fn overflow(patients: f64, capacity: f64) -> f64 {
+    if patients > capacity && patients > 0.0 {
+        (patients - capacity) / patients
+    } else {
+        0.0
     }
+}
 
-    pub fn simulate(self, start_time: Time, end_time: Time) -> Vec<State> {
-        let current_state = State::new(
-            *self.susceptible,
-            *self.exposed,
-            *self.infectious,
-            *self.removed,
-            *self.current_reproduction_number,
-        );
+/// Simulator based on SEIRD Model, with a vaccinated compartment fed from S and hospital and
+/// ICU compartments whose mortality rises once they run out of beds
+pub struct Simulator {
+    config: SimulatorConfig,
+    thresholds: Vec<Threshold>,
+}
 
-        let mut stepper = Dopri5::new(
-            self,
-            start_time,
-            end_time,
-            1.0,
-            current_state,
-            1.0e-10,
-            1.0e-10,
-        );
+impl Simulator {
+    pub fn new(config: SimulatorConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            thresholds: Vec::new(),
+        })
+    }
+
+    /// Watches `thresholds` when integrated by [`Deterministic::run`]
+    pub fn with_thresholds(mut self, thresholds: Vec<Threshold>) -> Result<Self, ConfigError> {
+        thresholds.iter().try_for_each(Threshold::validate)?;
+        self.thresholds = thresholds;
+        Ok(self)
+    }
+
+    pub fn config(&self) -> &SimulatorConfig {
+        &self.config
+    }
 
-        let _ = stepper.integrate().expect("Integration Error :(");
-        stepper.y_out().to_vec()
+    pub fn thresholds(&self) -> &[Threshold] {
+        &self.thresholds
+    }
+
+    fn initial_state(&self) -> State {
+        let state = &self.config.initial_state;
+        State::from([
+            state.susceptible,
+            state.exposed,
+            state.infectious,
+            state.removed,
+            state.current_reproduction_number,
+            state.deaths,
+            state.vaccinated,
+            state.hospitalized,
+            state.icu,
+            // Variants only ever come in through an event
+            0.0,
+            0.0,
+        ])
+    }
+
+    /// Parameters in effect at `time`, following the schedule of the config
+    pub fn parameters_at(&self, time: Time) -> &Parameters {
+        self.config
+            .schedule
+            .parameters_at(&self.config.parameters, time)
+    }
+
+    /// Rate of change of a region's state. `infectious_contacts` and `variant_contacts` are the
+    /// fractions infectious with each strain the region's population is in contact with, which
+    /// are just its own I when simulated alone.
+    fn derivatives(
+        &self,
+        t: Time,
+        y: &State,
+        infectious_contacts: f64,
+        variant_contacts: f64,
+        dy: &mut State,
+    ) {
+        let params = self.parameters_at(t);
+        let contact_rate =
+            params.recovery_rate * y[REPRODUCTION_NUMBER] * self.config.seasonality.factor(t);
+        let transmission = contact_rate * infectious_contacts;
+        // Vaccinated people can still be infected, at a rate reduced by the efficacy
+        let breakthrough = (1.0 - params.vaccine_efficacy) * transmission * y[VACCINATED];
+        let newly_vaccinated = params.vaccination_rate * y[SUSCEPTIBLE];
+        let waned = params.waning_rate * y[REMOVED];
+
+        // The variant also infects the part of the immune it escapes
+        let (variant_infections, variant_breakthrough, reinfections) = match &params.variant {
+            Some(variant) => {
+                let transmission = variant.transmissibility * contact_rate * variant_contacts;
+                let efficacy = params.vaccine_efficacy * (1.0 - variant.immunity_escape);
+                (
+                    (transmission + variant.importation_rate) * y[SUSCEPTIBLE],
+                    (1.0 - efficacy) * transmission * y[VACCINATED],
+                    variant.immunity_escape * transmission * y[REMOVED],
+                )
+            }
+            None => (0.0, 0.0, 0.0),
+        };
+
+        let leaving_infectious = params.recovery_rate * (y[INFECTIOUS] + y[INFECTIOUS_VARIANT]);
+        let hospitalized = params.hospitalization_rate * leaving_infectious;
+        // Everyone else leaving I either recovers or dies, split by the fatality rate
+        let not_hospitalized = leaving_infectious - hospitalized;
+
+        // Patients without a hospital bed die at the overflow rate instead of being treated
+        let leaving_hospital = params.hospital_stay_rate * y[HOSPITALIZED];
+        let hospital_deaths = overflow(y[HOSPITALIZED], params.hospital_capacity)
+            * params.overflow_fatality_rate
+            * leaving_hospital;
+        let to_icu = params.icu_rate * (leaving_hospital - hospital_deaths);
+
+        let leaving_icu = params.icu_stay_rate * y[ICU];
+        let icu_overflow = overflow(y[ICU], params.icu_capacity);
+        let icu_deaths = (params.icu_fatality_rate * (1.0 - icu_overflow)
+            + params.overflow_fatality_rate * icu_overflow)
+            * leaving_icu;
+
+        dy[SUSCEPTIBLE] =
+            -transmission * y[SUSCEPTIBLE] - newly_vaccinated + waned - variant_infections;
+        dy[VACCINATED] = newly_vaccinated - breakthrough - variant_breakthrough;
+        dy[EXPOSED] =
+            transmission * y[SUSCEPTIBLE] + breakthrough - params.infection_rate * y[EXPOSED];
+        dy[INFECTIOUS] = params.infection_rate * y[EXPOSED] - params.recovery_rate * y[INFECTIOUS];
+        dy[EXPOSED_VARIANT] = variant_infections + variant_breakthrough + reinfections
+            - params.infection_rate * y[EXPOSED_VARIANT];
+        dy[INFECTIOUS_VARIANT] = params.infection_rate * y[EXPOSED_VARIANT]
+            - params.recovery_rate * y[INFECTIOUS_VARIANT];
+        dy[HOSPITALIZED] = hospitalized - leaving_hospital;
+        dy[ICU] = to_icu - leaving_icu;
+        dy[REMOVED] = (1.0 - params.fatality_rate) * not_hospitalized
+            + (leaving_hospital - hospital_deaths - to_icu)
+            + (leaving_icu - icu_deaths)
+            - waned
+            - reinfections;
+        dy[DEATHS] = params.fatality_rate * not_hospitalized + hospital_deaths + icu_deaths;
+        dy[REPRODUCTION_NUMBER] =
+            params.compliance_factor * (params.ideal_reproduction_number - y[REPRODUCTION_NUMBER]);
+    }
+
+    pub fn simulate(self, start_time: Time, end_time: Time) -> Result<Vec<State>, SimulationError> {
+        Deterministic::default().simulate(self, start_time, end_time)
     }
 }
 
-impl<'a> ode_solvers::System<State> for Simulator<'a> {
-    fn system(&self, _t: Time, y: &State, dy: &mut State) {
-        // y[0..4] represent S, E, I, R and current reproduction number respectively
+/// A way of running a [`Simulator`] from `start_time` to `end_time`.
+///
+/// Every backend returns the state at the start time and after each following day.
+pub trait Backend {
+    fn simulate(
+        &mut self,
+        simulator: Simulator,
+        start_time: Time,
+        end_time: Time,
+    ) -> Result<Vec<State>, SimulationError>;
+}
 
-        dy[0] = -self.recovery_rate * y[4] * y[0] * y[2];
-        dy[1] = self.recovery_rate * y[4] * y[0] * y[2] - self.infection_rate * y[1];
-        dy[2] = self.infection_rate * y[1] - self.recovery_rate * y[2];
-        dy[3] = self.recovery_rate * y[2];
-        dy[4] = self.compliance_factor * (self.ideal_reproduction_number - y[4]);
+/// Integrates the SEIRD equations with a [`Solver`], Dopri5 unless told otherwise
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Deterministic {
+    solver: Solver,
+}
+
+impl Deterministic {
+    pub fn new(solver: Solver) -> Self {
+        Self { solver }
+    }
+
+    /// Integrates like [`Backend::simulate`] while watching the thresholds of the simulator.
+    /// Each crossing is located by root finding on the integrator, and the run ends at the
+    /// first crossing of a stopping threshold.
+    pub fn run(
+        &mut self,
+        simulator: Simulator,
+        start_time: Time,
+        end_time: Time,
+    ) -> Result<threshold::Run, SimulationError> {
+        self.solver.validate()?;
+        let solver = self.solver;
+        let segments = schedule::segments(start_time, end_time, simulator.config.schedule.days());
+        let mut crossings = Vec::new();
+        let mut stopped = false;
+        let trajectory = schedule::integrate_piecewise(
+            &segments,
+            simulator.initial_state(),
+            |from, to, current_state| {
+                // An empty segment ends the trajectory
+                if stopped {
+                    return Ok(Vec::new());
+                }
+                let mut segment = solver.integrate(&simulator, from, to, current_state)?;
+                for (day, pair) in segment.windows(2).enumerate() {
+                    let (before, after) = (&pair[0], &pair[1]);
+                    let start = from + day as Time;
+                    let end = (start + 1.0).min(to);
+                    let mut found = Vec::new();
+                    for (index, threshold) in
+                        threshold::crossed(&simulator.thresholds, before, after)
+                    {
+                        let (time, state) = threshold::locate(
+                            threshold,
+                            (start, before),
+                            (end, after),
+                            |(from, state), time| solver.state_at(&simulator, from, time, *state),
+                        )?;
+                        found.push(threshold::Crossing {
+                            threshold: index,
+                            time,
+                            state,
+                        });
+                    }
+                    threshold::sort(&mut found);
+                    let stop = found
+                        .iter()
+                        .position(|crossing| simulator.thresholds[crossing.threshold].stop);
+                    if let Some(stop) = stop {
+                        found.truncate(stop + 1);
+                        crossings.append(&mut found);
+                        stopped = true;
+                        segment.truncate(day + 1);
+                        break;
+                    }
+                    crossings.append(&mut found);
+                }
+                Ok(segment)
+            },
+        )?;
+        error::check_finite(&trajectory, start_time, |state| {
+            state.iter().all(|x| x.is_finite())
+        })?;
+        Ok(threshold::Run {
+            trajectory,
+            crossings,
+        })
+    }
+}
+
+impl Backend for Deterministic {
+    fn simulate(
+        &mut self,
+        simulator: Simulator,
+        start_time: Time,
+        end_time: Time,
+    ) -> Result<Vec<State>, SimulationError> {
+        Ok(self.run(simulator, start_time, end_time)?.trajectory)
     }
 }
 
-// TODO: Write tests
+impl ode_solvers::System<State> for &Simulator {
+    fn system(&self, t: Time, y: &State, dy: &mut State) {
+        self.derivatives(t, y, y[INFECTIOUS], y[INFECTIOUS_VARIANT], dy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A config going through every compartment, with too few beds, waning immunity and a
+    /// variant escaping part of it
+    fn builder() -> config::SimulatorConfigBuilder {
+        SimulatorConfig::builder()
+            .susceptible(0.98)
+            .exposed(0.0)
+            .infectious(0.01)
+            .removed(0.01)
+            .current_reproduction_number(2.5)
+            .ideal_reproduction_number(2.5)
+            .compliance_factor(0.1)
+            .recovery_rate(0.1)
+            .infection_rate(0.2)
+            .fatality_rate(0.01)
+            .vaccination_rate(0.002)
+            .vaccine_efficacy(0.8)
+            .hospitalization_rate(0.1)
+            .icu_rate(0.2)
+            .hospital_stay_rate(0.1)
+            .icu_stay_rate(0.1)
+            .icu_fatality_rate(0.3)
+            .overflow_fatality_rate(0.5)
+            .hospital_capacity(0.002)
+            .icu_capacity(0.0005)
+            .waning_rate(0.005)
+            .variant(Variant {
+                transmissibility: 1.5,
+                immunity_escape: 0.3,
+                importation_rate: 1.0e-5,
+            })
+    }
+
+    fn simulate(config: SimulatorConfig) -> Vec<State> {
+        Simulator::new(config)
+            .unwrap()
+            .simulate(0.0, 700.0)
+            .unwrap()
+    }
+
+    /// Everyone in the region, alive or dead
+    fn population(state: &State) -> f64 {
+        state.sum() - state[REPRODUCTION_NUMBER]
+    }
+
+    #[test]
+    fn population_is_conserved() {
+        let trajectory = simulate(builder().build().unwrap());
+        let initial = population(&trajectory[0]);
+        assert!((initial - 1.0).abs() < 1.0e-12);
+        for state in &trajectory {
+            assert!((population(state) - initial).abs() < 1.0e-8);
+            assert!(state.iter().all(|&value| value >= -1.0e-9));
+        }
+    }
+
+    #[test]
+    fn deaths_only_grow() {
+        let trajectory = simulate(builder().build().unwrap());
+        assert!(trajectory
+            .windows(2)
+            .all(|pair| pair[1][DEATHS] >= pair[0][DEATHS]));
+        assert!(trajectory.last().unwrap()[DEATHS] > 0.0);
+    }
+
+    #[test]
+    fn running_out_of_beds_kills_more() {
+        let overwhelmed = simulate(builder().build().unwrap());
+        let enough_beds = simulate(
+            builder()
+                .hospital_capacity(1.0)
+                .icu_capacity(1.0)
+                .build()
+                .unwrap(),
+        );
+        assert!(overwhelmed.last().unwrap()[DEATHS] > enough_beds.last().unwrap()[DEATHS]);
+    }
+
+    #[test]
+    fn variant_spreads_once_introduced() {
+        let config = builder().build().unwrap();
+        let mut without = config.clone();
+        without.parameters.variant = None;
+        let with_variant = simulate(config);
+        assert!(with_variant
+            .iter()
+            .any(|state| state[INFECTIOUS_VARIANT] > 1.0e-3));
+        assert!(simulate(without)
+            .iter()
+            .all(|state| state[INFECTIOUS_VARIANT] == 0.0));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::config::check_range;
+use crate::error::check_finite;
+use crate::schedule::{integrate_piecewise, segments};
+use crate::{ConfigError, Solver};
+use crate::{SimulationError, Simulator, State, Time, INFECTIOUS, INFECTIOUS_VARIANT, STATE_SIZE};
+use ode_solvers::DVector;
+
+type CoupledState = DVector<f64>;
+
+/// Simulates several regions together, coupled through a mobility matrix.
+///
+/// `mobility[i][j]` is the fraction of the contacts of people living in region `i` that
+/// happen with people from region `j`. The diagonal is ignored, region `i` keeps whatever
+/// share of its contacts is left after the other entries of its row. Every entry going in or
+/// out of a region is scaled by the mobility factor of the region at the time, e.g. when its
+/// borders are closed.
+pub struct Metapopulation {
+    regions: Vec<Simulator>,
+    mobility: Vec<Vec<f64>>,
+    solver: Solver,
+}
+
+impl Metapopulation {
+    /// Fails unless the matrix is square with a row per region and its entries are fractions
+    pub fn new(regions: Vec<Simulator>, mobility: Vec<Vec<f64>>) -> Result<Self, ConfigError> {
+        let shape = ConfigError::MobilityShape {
+            regions: regions.len(),
+        };
+        if mobility.len() != regions.len() {
+            return Err(shape);
+        }
+        for row in &mobility {
+            if row.len() != regions.len() {
+                return Err(shape);
+            }
+            for &entry in row {
+                check_range("mobility", entry, 0.0, 1.0)?;
+            }
+        }
+        Ok(Self {
+            regions,
+            mobility,
+            solver: Solver::default(),
+        })
+    }
+
+    pub fn with_solver(mut self, solver: Solver) -> Self {
+        self.solver = solver;
+        self
+    }
+
+    /// Returns the trajectory of every region, in the same order as they were passed in
+    pub fn simulate(
+        self,
+        start_time: Time,
+        end_time: Time,
+    ) -> Result<Vec<Vec<State>>, SimulationError> {
+        let region_count = self.regions.len();
+        let current_state = CoupledState::from_iterator(
+            region_count * STATE_SIZE,
+            self.regions
+                .iter()
+                .flat_map(|region| region.initial_state().as_slice().to_vec()),
+        );
+
+        // The parameters of the system change whenever those of one of its regions do
+        let segments = segments(
+            start_time,
+            end_time,
+            self.regions
+                .iter()
+                .flat_map(|region| region.config.schedule.days()),
+        );
+        let trajectory =
+            integrate_piecewise(&segments, current_state, |from, to, current_state| {
+                self.solver.integrate(&self, from, to, current_state)
+            })?;
+        check_finite(&trajectory, start_time, |y| y.iter().all(|x| x.is_finite()))?;
+        Ok((0..region_count)
+            .map(|region| trajectory.iter().map(|y| region_state(y, region)).collect())
+            .collect())
+    }
+}
+
+fn region_state(y: &CoupledState, region: usize) -> State {
+    State::from_iterator(y.rows(region * STATE_SIZE, STATE_SIZE).iter().cloned())
+}
+
+impl ode_solvers::System<CoupledState> for &Metapopulation {
+    fn system(&self, t: Time, y: &CoupledState, dy: &mut CoupledState) {
+        let states: Vec<State> = (0..self.regions.len())
+            .map(|region| region_state(y, region))
+            .collect();
+        let mobility_factors: Vec<f64> = self
+            .regions
+            .iter()
+            .map(|region| region.parameters_at(t).mobility_factor)
+            .collect();
+
+        for (i, (region, state)) in self.regions.iter().zip(states.iter()).enumerate() {
+            let contacts = |compartment: usize| {
+                let mut contacts = state[compartment];
+                for (j, other) in states.iter().enumerate() {
+                    if i != j {
+                        contacts += self.mobility[i][j]
+                            * mobility_factors[i]
+                            * mobility_factors[j]
+                            * (other[compartment] - state[compartment]);
+                    }
+                }
+                contacts
+            };
+
+            let mut region_dy = State::zeros();
+            region.derivatives(
+                t,
+                state,
+                contacts(INFECTIOUS),
+                contacts(INFECTIOUS_VARIANT),
+                &mut region_dy,
+            );
+            dy.rows_mut(i * STATE_SIZE, STATE_SIZE)
+                .copy_from(&region_dy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimulatorConfig;
+
+    fn region(infectious: f64) -> Simulator {
+        Simulator::new(
+            SimulatorConfig::builder()
+                .susceptible(1.0 - infectious)
+                .exposed(0.0)
+                .infectious(infectious)
+                .removed(0.0)
+                .current_reproduction_number(2.0)
+                .ideal_reproduction_number(2.0)
+                .compliance_factor(0.1)
+                .recovery_rate(0.1)
+                .infection_rate(0.2)
+                .build()
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_matrices_that_dont_match_the_regions() {
+        let missing_row = vec![vec![0.0, 0.1]];
+        assert_eq!(
+            Metapopulation::new(vec![region(0.01), region(0.0)], missing_row).err(),
+            Some(ConfigError::MobilityShape { regions: 2 })
+        );
+        let short_row = vec![vec![0.0, 0.1], vec![0.1]];
+        assert_eq!(
+            Metapopulation::new(vec![region(0.01), region(0.0)], short_row).err(),
+            Some(ConfigError::MobilityShape { regions: 2 })
+        );
+    }
+
+    #[test]
+    fn rejects_entries_that_arent_fractions() {
+        for entry in [-0.1, f64::NAN, f64::INFINITY] {
+            let mobility = vec![vec![0.0, entry], vec![0.1, 0.0]];
+            assert!(matches!(
+                Metapopulation::new(vec![region(0.01), region(0.0)], mobility),
+                Err(ConfigError::OutOfRange { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn spreads_to_coupled_regions_and_conserves_population() {
+        let mobility = vec![vec![0.0, 0.1], vec![0.1, 0.0]];
+        let trajectories = Metapopulation::new(vec![region(0.01), region(0.0)], mobility)
+            .unwrap()
+            .simulate(0.0, 200.0)
+            .unwrap();
+        let last = trajectories[1].last().unwrap();
+        assert!(last[crate::REMOVED] > 0.1);
+        for trajectory in &trajectories {
+            for state in trajectory {
+                let total = state.sum() - state[crate::REPRODUCTION_NUMBER];
+                assert!((total - 1.0).abs() < 1.0e-6);
+            }
+        }
+    }
+}
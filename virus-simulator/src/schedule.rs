@@ -0,0 +1,201 @@
+use crate::{ConfigError, Parameters, SimulationError, Time};
+use serde::{Deserialize, Serialize};
+
+/// Parameters taking effect on a day, until the next change
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ParameterChange {
+    pub day: u32,
+    #[serde(flatten)]
+    pub parameters: Parameters,
+}
+
+/// Piecewise constant parameters, e.g. a lockdown from day 40 to day 90 is a change on day 40
+/// and another one on day 90 going back to the previous parameters.
+///
+/// Before the first change the simulation runs with the parameters of its
+/// [`crate::SimulatorConfig`]. Serializes to the list of changes, ordered by day.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(from = "Vec<ParameterChange>", into = "Vec<ParameterChange>")]
+pub struct Schedule {
+    changes: Vec<ParameterChange>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a change, replacing the one already planned on the same day if any
+    pub fn insert(&mut self, day: u32, parameters: Parameters) {
+        match self.changes.binary_search_by_key(&day, |change| change.day) {
+            Ok(index) => self.changes[index].parameters = parameters,
+            Err(index) => self
+                .changes
+                .insert(index, ParameterChange { day, parameters }),
+        }
+    }
+
+    pub fn with_change(mut self, day: u32, parameters: Parameters) -> Self {
+        self.insert(day, parameters);
+        self
+    }
+
+    pub fn changes(&self) -> &[ParameterChange] {
+        &self.changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Parameters in effect at `time`, `base` if no change happened yet
+    pub fn parameters_at<'a>(&'a self, base: &'a Parameters, time: Time) -> &'a Parameters {
+        self.changes
+            .iter()
+            .take_while(|change| change.day as Time <= time)
+            .last()
+            .map_or(base, |change| &change.parameters)
+    }
+
+    pub(crate) fn days(&self) -> impl Iterator<Item = Time> + '_ {
+        self.changes.iter().map(|change| change.day as Time)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.changes
+            .iter()
+            .try_for_each(|change| change.parameters.validate())
+    }
+}
+
+impl From<Vec<ParameterChange>> for Schedule {
+    fn from(changes: Vec<ParameterChange>) -> Self {
+        changes
+            .into_iter()
+            .fold(Schedule::new(), |schedule, change| {
+                schedule.with_change(change.day, change.parameters)
+            })
+    }
+}
+
+impl From<Schedule> for Vec<ParameterChange> {
+    fn from(schedule: Schedule) -> Self {
+        schedule.changes
+    }
+}
+
+/// Splits `start_time..end_time` on every day in `days` that falls inside it, so that the
+/// parameters are constant over each of the returned intervals
+pub(crate) fn segments(
+    start_time: Time,
+    end_time: Time,
+    days: impl Iterator<Item = Time>,
+) -> Vec<(Time, Time)> {
+    let mut bounds: Vec<Time> = days
+        .filter(|&day| day > start_time && day < end_time)
+        .collect();
+    bounds.sort_by(|a, b| a.total_cmp(b));
+    bounds.dedup();
+    bounds.insert(0, start_time);
+    bounds.push(end_time);
+    bounds.windows(2).map(|pair| (pair[0], pair[1])).collect()
+}
+
+/// Integrates one segment at a time so that the solver never steps over a parameter change.
+/// `integrate` gets the bounds of a segment and the state at its start, and returns one state
+/// per day from the start of the segment up to and including its end.
+pub(crate) fn integrate_piecewise<V: Clone>(
+    segments: &[(Time, Time)],
+    initial_state: V,
+    mut integrate: impl FnMut(Time, Time, V) -> Result<Vec<V>, SimulationError>,
+) -> Result<Vec<V>, SimulationError> {
+    let mut trajectory: Vec<V> = Vec::new();
+    let mut state = initial_state;
+    for &(from, to) in segments {
+        let mut segment = integrate(from, to, state)?;
+        state = match segment.last() {
+            Some(last) => last.clone(),
+            None => return Ok(trajectory),
+        };
+        // The first state of a segment is the last one of the previous segment
+        if !trajectory.is_empty() {
+            segment.remove(0);
+        }
+        trajectory.append(&mut segment);
+    }
+    Ok(trajectory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_split_on_the_days_inside_the_range() {
+        let days = [90.0, 40.0, 40.0, 0.0, 150.0].into_iter();
+        assert_eq!(
+            segments(0.0, 120.0, days),
+            vec![(0.0, 40.0), (40.0, 90.0), (90.0, 120.0)]
+        );
+        assert_eq!(segments(0.0, 120.0, std::iter::empty()), vec![(0.0, 120.0)]);
+    }
+
+    #[test]
+    fn integrate_piecewise_joins_segments_without_repeating_their_bounds() {
+        let segments = segments(0.0, 10.0, [3.0, 7.0].into_iter());
+        let trajectory = integrate_piecewise(&segments, 0.0, |from, to, state| {
+            assert_eq!(state, from);
+            Ok((from as u32..=to as u32).map(Time::from).collect())
+        })
+        .unwrap();
+        assert_eq!(trajectory, (0..=10).map(Time::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn integrate_piecewise_ends_at_an_empty_segment() {
+        let segments = segments(0.0, 10.0, [3.0, 7.0].into_iter());
+        let trajectory = integrate_piecewise(&segments, 0.0, |from, to, _| {
+            if from >= 7.0 {
+                return Ok(Vec::new());
+            }
+            Ok((from as u32..=to as u32).map(Time::from).collect())
+        })
+        .unwrap();
+        assert_eq!(trajectory, (0..=7).map(Time::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parameters_follow_the_latest_change() {
+        let base = crate::SimulatorConfig::builder()
+            .susceptible(0.99)
+            .exposed(0.0)
+            .infectious(0.01)
+            .removed(0.0)
+            .current_reproduction_number(2.5)
+            .ideal_reproduction_number(2.5)
+            .compliance_factor(0.1)
+            .recovery_rate(0.1)
+            .infection_rate(0.2)
+            .build()
+            .unwrap()
+            .parameters;
+        let lockdown = Parameters {
+            ideal_reproduction_number: 0.8,
+            ..base.clone()
+        };
+        let reopening = Parameters {
+            ideal_reproduction_number: 1.5,
+            ..base.clone()
+        };
+        let mut schedule = Schedule::new()
+            .with_change(90, base.clone())
+            .with_change(40, reopening.clone());
+        // Replaces the change already planned on day 40
+        schedule.insert(40, lockdown.clone());
+
+        assert_eq!(schedule.changes().len(), 2);
+        assert_eq!(schedule.parameters_at(&reopening, 39.9), &reopening);
+        assert_eq!(schedule.parameters_at(&reopening, 40.0), &lockdown);
+        assert_eq!(schedule.parameters_at(&reopening, 120.0), &base);
+    }
+}
@@ -0,0 +1,97 @@
+//! Seasonal changes in how easily the virus spreads, e.g. winter waves. Transmission is
+//! multiplied by a factor following the time of year, the reproduction numbers of the
+//! parameters being the baseline it modulates.
+use crate::config::check_range;
+use crate::{ConfigError, Time};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Multiplier on transmission over the course of a simulation. Times are days since the start
+/// of the level.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Seasonality {
+    /// Transmission stays at its baseline
+    #[default]
+    None,
+    /// `1 + amplitude * cos(2π (t - phase) / period)`
+    Sinusoidal {
+        /// Fraction by which transmission rises above and falls below its baseline
+        amplitude: f64,
+        /// Day on which transmission peaks
+        phase: f64,
+        #[serde(default = "year")]
+        period: f64,
+    },
+    /// The period is split into as many equal pieces as there are multipliers, e.g. twelve
+    /// for one per month
+    Piecewise {
+        multipliers: Vec<f64>,
+        /// Day on which the first piece starts
+        #[serde(default)]
+        phase: f64,
+        #[serde(default = "year")]
+        period: f64,
+    },
+}
+
+fn year() -> f64 {
+    365.0
+}
+
+impl Seasonality {
+    pub fn is_none(&self) -> bool {
+        *self == Seasonality::None
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match self {
+            Seasonality::None => Ok(()),
+            Seasonality::Sinusoidal {
+                amplitude,
+                phase,
+                period,
+            } => {
+                // Transmission can't go negative
+                check_range("amplitude", *amplitude, 0.0, 1.0)?;
+                check_range("period", *period, 1.0, 3650.0)?;
+                check_range("phase", *phase, -*period, *period)
+            }
+            Seasonality::Piecewise {
+                multipliers,
+                phase,
+                period,
+            } => {
+                for multiplier in multipliers {
+                    check_range("multipliers", *multiplier, 0.0, 10.0)?;
+                }
+                check_range("period", *period, 1.0, 3650.0)?;
+                check_range("phase", *phase, -*period, *period)
+            }
+        }
+    }
+
+    /// Factor transmission is multiplied by at `time`
+    pub fn factor(&self, time: Time) -> f64 {
+        match self {
+            Seasonality::None => 1.0,
+            Seasonality::Sinusoidal {
+                amplitude,
+                phase,
+                period,
+            } => 1.0 + amplitude * (2.0 * PI * (time - phase) / period).cos(),
+            Seasonality::Piecewise {
+                multipliers,
+                phase,
+                period,
+            } => {
+                if multipliers.is_empty() {
+                    return 1.0;
+                }
+                let share = (time - phase).rem_euclid(*period) / period;
+                let piece = (share * multipliers.len() as f64) as usize;
+                multipliers[piece.min(multipliers.len() - 1)]
+            }
+        }
+    }
+}
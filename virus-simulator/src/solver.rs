@@ -0,0 +1,551 @@
+//! Numerical methods the deterministic backends integrate the model with
+use crate::config::check_range;
+use crate::{ConfigError, SimulationError, State, Time};
+use ode_solvers::dop_shared::{IntegrationError, OutputType};
+use ode_solvers::{DVector, Dop853, Dopri5, System};
+use serde::{Deserialize, Serialize};
+
+/// Method and tolerances used to integrate the model. Whatever the method, a state is output
+/// at the start time, after each following day and at the end time.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Solver {
+    /// Classic fourth order Runge-Kutta with a fixed step. The fastest, but nothing checks
+    /// its error.
+    Rk4 {
+        #[serde(default = "one_step_per_day")]
+        steps_per_day: u32,
+    },
+    /// Dormand-Prince 5(4) with adaptive steps
+    Dopri5 {
+        #[serde(default = "tight_tolerance")]
+        rtol: f64,
+        #[serde(default = "tight_tolerance")]
+        atol: f64,
+    },
+    /// Dormand-Prince 8(5, 3) with adaptive steps, takes fewer of them than Dopri5 with tight
+    /// tolerances
+    Dop853 {
+        #[serde(default = "tight_tolerance")]
+        rtol: f64,
+        #[serde(default = "tight_tolerance")]
+        atol: f64,
+    },
+    /// Second order Rosenbrock method (ROS2) with adaptive steps. Being linearly implicit it
+    /// stays stable when the system is stiff, e.g. with a very fast compliance factor.
+    Rosenbrock {
+        #[serde(default = "loose_tolerance")]
+        rtol: f64,
+        #[serde(default = "loose_absolute_tolerance")]
+        atol: f64,
+    },
+}
+
+fn one_step_per_day() -> u32 {
+    1
+}
+
+/// What the simulator always integrated with before the solver could be chosen
+fn tight_tolerance() -> f64 {
+    1.0e-10
+}
+
+/// A second order method needs far too many steps to reach tight tolerances
+fn loose_tolerance() -> f64 {
+    1.0e-6
+}
+
+/// Levels start with about a millionth of the population infected, which must stay well above
+/// the absolute tolerance for the start of the outbreak to be followed closely
+fn loose_absolute_tolerance() -> f64 {
+    1.0e-9
+}
+
+impl Default for Solver {
+    fn default() -> Self {
+        Solver::Dopri5 {
+            rtol: tight_tolerance(),
+            atol: tight_tolerance(),
+        }
+    }
+}
+
+/// Steps the adaptive methods may take between two outputs before giving up
+const MAX_STEPS: u32 = 100_000;
+
+impl Solver {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match *self {
+            Solver::Rk4 { steps_per_day } => {
+                check_range("steps_per_day", steps_per_day as f64, 1.0, 1.0e4)
+            }
+            Solver::Dopri5 { rtol, atol }
+            | Solver::Dop853 { rtol, atol }
+            | Solver::Rosenbrock { rtol, atol } => {
+                check_range("rtol", rtol, f64::EPSILON, 1.0)?;
+                check_range("atol", atol, f64::EPSILON, 1.0)
+            }
+        }
+    }
+
+    /// Integrates `system` from `from` to `to`, starting from `y`
+    pub(crate) fn integrate<V, F>(
+        &self,
+        system: F,
+        from: Time,
+        to: Time,
+        y: V,
+    ) -> Result<Vec<V>, SimulationError>
+    where
+        V: Vector,
+        F: System<V>,
+    {
+        match *self {
+            Solver::Dopri5 { rtol, atol } => Ok(V::dopri5(
+                system,
+                from,
+                to,
+                y,
+                rtol,
+                atol,
+                OutputType::Dense,
+            )?),
+            Solver::Dop853 { rtol, atol } => Ok(V::dop853(
+                system,
+                from,
+                to,
+                y,
+                rtol,
+                atol,
+                OutputType::Dense,
+            )?),
+            Solver::Rk4 { steps_per_day } => Ok(rk4(&system, from, to, y, steps_per_day)),
+            Solver::Rosenbrock { rtol, atol } => rosenbrock(&system, from, to, y, rtol, atol),
+        }
+    }
+
+    /// State reached by integrating `system` from `from` to `to`, starting from `y`. Unlike
+    /// [`Solver::integrate`] `to` doesn't need to be a whole number of days from `from`.
+    pub(crate) fn state_at<V, F>(
+        &self,
+        system: F,
+        from: Time,
+        to: Time,
+        y: V,
+    ) -> Result<V, SimulationError>
+    where
+        V: Vector,
+        F: System<V>,
+    {
+        let start = y.clone();
+        // The adaptive methods only output the state at the end when asked for every step
+        let mut states = match *self {
+            Solver::Dopri5 { rtol, atol } => {
+                V::dopri5(system, from, to, y, rtol, atol, OutputType::Sparse)?
+            }
+            Solver::Dop853 { rtol, atol } => {
+                V::dop853(system, from, to, y, rtol, atol, OutputType::Sparse)?
+            }
+            Solver::Rk4 { .. } | Solver::Rosenbrock { .. } => {
+                self.integrate(system, from, to, y)?
+            }
+        };
+        // Every method outputs at least the state it started from
+        Ok(states.pop().unwrap_or(start))
+    }
+}
+
+/// States the solvers integrate, the methods written here work on their values
+pub(crate) trait Vector: Clone + Sized {
+    fn values(&self) -> &[f64];
+    fn from_values(values: &[f64]) -> Self;
+    fn dopri5<F: System<Self>>(
+        system: F,
+        from: Time,
+        to: Time,
+        y: Self,
+        rtol: f64,
+        atol: f64,
+        output: OutputType,
+    ) -> Result<Vec<Self>, IntegrationError>;
+    fn dop853<F: System<Self>>(
+        system: F,
+        from: Time,
+        to: Time,
+        y: Self,
+        rtol: f64,
+        atol: f64,
+        output: OutputType,
+    ) -> Result<Vec<Self>, IntegrationError>;
+}
+
+macro_rules! impl_vector {
+    ($vector:ty) => {
+        impl Vector for $vector {
+            fn values(&self) -> &[f64] {
+                self.as_slice()
+            }
+
+            fn from_values(values: &[f64]) -> Self {
+                <$vector>::from_column_slice(values)
+            }
+
+            fn dopri5<F: System<Self>>(
+                system: F,
+                from: Time,
+                to: Time,
+                y: Self,
+                rtol: f64,
+                atol: f64,
+                output: OutputType,
+            ) -> Result<Vec<Self>, IntegrationError> {
+                // The settings of Dopri5::new, which always outputs every day
+                let mut stepper = Dopri5::from_param(
+                    system,
+                    from,
+                    to,
+                    1.0,
+                    y,
+                    rtol,
+                    atol,
+                    0.9,
+                    0.04,
+                    0.2,
+                    10.0,
+                    to - from,
+                    0.0,
+                    MAX_STEPS,
+                    1000,
+                    output,
+                );
+                stepper.integrate()?;
+                Ok(stepper.y_out().to_vec())
+            }
+
+            fn dop853<F: System<Self>>(
+                system: F,
+                from: Time,
+                to: Time,
+                y: Self,
+                rtol: f64,
+                atol: f64,
+                output: OutputType,
+            ) -> Result<Vec<Self>, IntegrationError> {
+                // The settings of Dop853::new, which always outputs every day
+                let mut stepper = Dop853::from_param(
+                    system,
+                    from,
+                    to,
+                    1.0,
+                    y,
+                    rtol,
+                    atol,
+                    0.9,
+                    0.0,
+                    0.333,
+                    6.0,
+                    to - from,
+                    0.0,
+                    MAX_STEPS,
+                    1000,
+                    output,
+                );
+                stepper.integrate()?;
+                Ok(stepper.y_out().to_vec())
+            }
+        }
+    };
+}
+
+impl_vector!(State);
+impl_vector!(DVector<f64>);
+
+/// Start of every day from `from` up to `to`, and `to` itself
+fn output_times(from: Time, to: Time) -> Vec<Time> {
+    // Slack so that rounding errors don't add an output right before `to`
+    let mut times: Vec<Time> = (0..)
+        .map(|day| from + day as Time)
+        .take_while(|&time| time < to - 1.0e-9)
+        .collect();
+    times.push(to);
+    times
+}
+
+fn derivatives<V: Vector, F: System<V>>(system: &F, t: Time, y: &[f64]) -> Vec<f64> {
+    let y = V::from_values(y);
+    let mut dy = y.clone();
+    system.system(t, &y, &mut dy);
+    dy.values().to_vec()
+}
+
+/// `a + factor * b`
+fn add_scaled(a: &[f64], factor: f64, b: &[f64]) -> Vec<f64> {
+    a.iter().zip(b).map(|(a, b)| a + factor * b).collect()
+}
+
+fn rk4<V: Vector, F: System<V>>(
+    system: &F,
+    from: Time,
+    to: Time,
+    y: V,
+    steps_per_day: u32,
+) -> Vec<V> {
+    let times = output_times(from, to);
+    let mut trajectory = Vec::with_capacity(times.len());
+    let mut y = y.values().to_vec();
+    trajectory.push(V::from_values(&y));
+    for pair in times.windows(2) {
+        // Outputs closer than a day, at the end of a segment, get fewer steps
+        let steps = ((pair[1] - pair[0]) * steps_per_day as f64).ceil().max(1.0) as u32;
+        let h = (pair[1] - pair[0]) / steps as f64;
+        for step in 0..steps {
+            let t = pair[0] + step as f64 * h;
+            let k1 = derivatives(system, t, &y);
+            let k2 = derivatives(system, t + h / 2.0, &add_scaled(&y, h / 2.0, &k1));
+            let k3 = derivatives(system, t + h / 2.0, &add_scaled(&y, h / 2.0, &k2));
+            let k4 = derivatives(system, t + h, &add_scaled(&y, h, &k3));
+            for (i, value) in y.iter_mut().enumerate() {
+                *value += h / 6.0 * (k1[i] + 2.0 * k2[i] + 2.0 * k3[i] + k4[i]);
+            }
+        }
+        trajectory.push(V::from_values(&y));
+    }
+    trajectory
+}
+
+/// LU decomposition with partial pivoting of a square matrix stored by rows
+struct Lu {
+    rows: Vec<Vec<f64>>,
+    pivots: Vec<usize>,
+}
+
+impl Lu {
+    /// `None` when the matrix is singular
+    fn new(mut rows: Vec<Vec<f64>>) -> Option<Self> {
+        let n = rows.len();
+        let mut pivots = Vec::with_capacity(n);
+        for k in 0..n {
+            let pivot = (k..n).max_by(|&a, &b| rows[a][k].abs().total_cmp(&rows[b][k].abs()))?;
+            if rows[pivot][k] == 0.0 || !rows[pivot][k].is_finite() {
+                return None;
+            }
+            rows.swap(k, pivot);
+            pivots.push(pivot);
+            let (above, below) = rows.split_at_mut(k + 1);
+            let pivot_row = &above[k];
+            for row in below {
+                let factor = row[k] / pivot_row[k];
+                row[k] = factor;
+                for (value, pivot_value) in row[k + 1..].iter_mut().zip(&pivot_row[k + 1..]) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        }
+        Some(Lu { rows, pivots })
+    }
+
+    fn solve(&self, b: &[f64]) -> Vec<f64> {
+        let n = self.rows.len();
+        let mut x = b.to_vec();
+        for (k, &pivot) in self.pivots.iter().enumerate() {
+            x.swap(k, pivot);
+        }
+        let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+        for i in 0..n {
+            x[i] -= dot(&self.rows[i][..i], &x[..i]);
+        }
+        for i in (0..n).rev() {
+            x[i] = (x[i] - dot(&self.rows[i][i + 1..], &x[i + 1..])) / self.rows[i][i];
+        }
+        x
+    }
+}
+
+/// `1 - factor * J`, with J the Jacobian of `system` at `(t, y)` by forward differences
+fn implicit_matrix<V: Vector, F: System<V>>(
+    system: &F,
+    t: Time,
+    y: &[f64],
+    dy: &[f64],
+    factor: f64,
+) -> Vec<Vec<f64>> {
+    let n = y.len();
+    let mut rows = vec![vec![0.0; n]; n];
+    let mut shifted = y.to_vec();
+    for column in 0..n {
+        let delta = f64::EPSILON.sqrt() * y[column].abs().max(1.0e-5);
+        shifted[column] += delta;
+        let shifted_dy = derivatives(system, t, &shifted);
+        for (row, (shifted, dy)) in rows.iter_mut().zip(shifted_dy.iter().zip(dy)) {
+            row[column] = -factor * (shifted - dy) / delta;
+        }
+        shifted[column] = y[column];
+        rows[column][column] += 1.0;
+    }
+    rows
+}
+
+fn rosenbrock<V: Vector, F: System<V>>(
+    system: &F,
+    from: Time,
+    to: Time,
+    y: V,
+    rtol: f64,
+    atol: f64,
+) -> Result<Vec<V>, SimulationError> {
+    let gamma = 1.0 + 1.0 / 2.0_f64.sqrt();
+
+    let times = output_times(from, to);
+    let mut trajectory = Vec::with_capacity(times.len());
+    let mut y = y.values().to_vec();
+    trajectory.push(V::from_values(&y));
+    let mut t = from;
+    let mut h: f64 = 0.1;
+    for &output in &times[1..] {
+        let mut steps = 0;
+        // Slack so that rounding errors don't leave a sliver of a step before the output
+        while output - t > 1.0e-9 {
+            steps += 1;
+            if steps > MAX_STEPS {
+                return Err(SimulationError::TooManySteps {
+                    time: t,
+                    steps: MAX_STEPS,
+                });
+            }
+            if h < 1.0e-12 {
+                return Err(SimulationError::StepSizeUnderflow { time: t });
+            }
+            // Lands exactly on the output instead of interpolating
+            let step = h.min(output - t);
+
+            let dy = derivatives(system, t, &y);
+            let lu = match Lu::new(implicit_matrix(system, t, &y, &dy, gamma * step)) {
+                Some(lu) => lu,
+                None => {
+                    h = step / 2.0;
+                    continue;
+                }
+            };
+            let k1 = lu.solve(&dy);
+            let rhs = add_scaled(
+                &derivatives(system, t + step, &add_scaled(&y, step, &k1)),
+                -2.0,
+                &k1,
+            );
+            let k2 = lu.solve(&rhs);
+            let next: Vec<f64> = (0..y.len())
+                .map(|i| y[i] + step * (1.5 * k1[i] + 0.5 * k2[i]))
+                .collect();
+
+            // Difference with the embedded linearly implicit Euler step
+            let norm = ((0..y.len())
+                .map(|i| {
+                    let error = 0.5 * step * (k1[i] + k2[i]);
+                    (error / (atol + rtol * y[i].abs().max(next[i].abs()))).powi(2)
+                })
+                .sum::<f64>()
+                / y.len() as f64)
+                .sqrt();
+
+            if !norm.is_finite() {
+                h = step / 2.0;
+                continue;
+            }
+            let next_h = step * (0.9 / norm.max(1.0e-10).sqrt()).clamp(0.2, 5.0);
+            if norm <= 1.0 {
+                t += step;
+                y = next;
+                // A step cut short to land on the output says little about the next one
+                h = if step < h { h.max(next_h) } else { next_h };
+            } else {
+                h = next_h;
+            }
+        }
+        // Rounding must not move the following outputs
+        t = output;
+        trajectory.push(V::from_values(&y));
+    }
+    Ok(trajectory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level::Start;
+    use crate::{Backend, Deterministic, Simulator, SimulatorConfig};
+    use std::path::Path;
+
+    const DAYS: Time = 700.0;
+
+    /// Regions of the first shipped level, which isn't stiff
+    fn level_regions() -> Vec<SimulatorConfig> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../src/game/levels/1/start.json");
+        let start: Start = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        start.regions().map(|(_, config)| config).collect()
+    }
+
+    fn simulate(solver: Solver, config: &SimulatorConfig) -> Vec<State> {
+        let simulator = Simulator::new(config.clone()).unwrap();
+        Deterministic::new(solver)
+            .simulate(simulator, 0.0, DAYS)
+            .unwrap()
+    }
+
+    /// Largest difference on any compartment and day, as a fraction of the population
+    fn max_error(trajectory: &[State], reference: &[State]) -> f64 {
+        assert_eq!(trajectory.len(), reference.len());
+        trajectory
+            .iter()
+            .zip(reference)
+            .map(|(state, reference)| (state - reference).amax())
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn rosenbrock_matches_dopri5_on_a_level() {
+        let rosenbrock = Solver::Rosenbrock {
+            rtol: loose_tolerance(),
+            atol: loose_absolute_tolerance(),
+        };
+        for config in level_regions() {
+            let reference = simulate(Solver::default(), &config);
+            assert!(max_error(&simulate(rosenbrock, &config), &reference) < 5.0e-4);
+        }
+    }
+
+    #[test]
+    fn rk4_converges_to_dopri5_with_smaller_steps() {
+        for config in level_regions() {
+            let reference = simulate(Solver::default(), &config);
+            let coarse = max_error(
+                &simulate(Solver::Rk4 { steps_per_day: 1 }, &config),
+                &reference,
+            );
+            let fine = max_error(
+                &simulate(Solver::Rk4 { steps_per_day: 8 }, &config),
+                &reference,
+            );
+            assert!(fine < 1.0e-6);
+            assert!(fine <= coarse);
+        }
+    }
+
+    #[test]
+    fn state_at_matches_the_daily_output() {
+        let config = &level_regions()[0];
+        let simulator = Simulator::new(config.clone()).unwrap();
+        let y = simulator.initial_state();
+        for solver in [
+            Solver::default(),
+            Solver::Rk4 { steps_per_day: 4 },
+            Solver::Rosenbrock {
+                rtol: loose_tolerance(),
+                atol: loose_absolute_tolerance(),
+            },
+        ] {
+            let daily = solver.integrate(&simulator, 0.0, 10.0, y).unwrap();
+            let state = solver.state_at(&simulator, 0.0, 10.0, y).unwrap();
+            assert!((state - daily[10]).amax() < 1.0e-9);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{
+    overflow, Backend, SimulationError, Simulator, State, Time, DEATHS, EXPOSED, EXPOSED_VARIANT,
+    HOSPITALIZED, ICU, INFECTIOUS, INFECTIOUS_VARIANT, REMOVED, REPRODUCTION_NUMBER, SUSCEPTIBLE,
+    VACCINATED,
+};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Binomial, Distribution};
+
+/// Runs the model on whole people instead of population fractions, drawing every
+/// transition from a binomial distribution over small time steps (tau-leaping).
+///
+/// Outbreaks in small populations can die out or flare up by chance, which the
+/// deterministic backend can't show. Runs with the same seed produce the same trajectory.
+pub struct TauLeaping {
+    population: f64,
+    steps_per_day: u32,
+    rng: StdRng,
+}
+
+impl TauLeaping {
+    pub fn new(population: f64, seed: u64) -> Self {
+        Self {
+            population,
+            steps_per_day: 10,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn with_steps_per_day(mut self, steps_per_day: u32) -> Self {
+        self.steps_per_day = steps_per_day.max(1);
+        self
+    }
+
+    /// Number of people out of `count` making a transition with the given rate over `tau`
+    fn transitions(&mut self, count: f64, rate: f64, tau: f64) -> f64 {
+        let probability = 1.0 - (-rate * tau).exp();
+        self.draw(count, probability)
+    }
+
+    fn draw(&mut self, count: f64, probability: f64) -> f64 {
+        if count < 1.0 || probability <= 0.0 {
+            return 0.0;
+        }
+        match Binomial::new(count as u64, probability.min(1.0)) {
+            Ok(distribution) => distribution.sample(&mut self.rng) as f64,
+            Err(_) => 0.0,
+        }
+    }
+
+    fn step(&mut self, sim: &Simulator, t: Time, y: &mut State, tau: f64) {
+        let params = sim.parameters_at(t);
+        let contact_rate =
+            params.recovery_rate * y[REPRODUCTION_NUMBER] * sim.config().seasonality.factor(t)
+                / self.population;
+        let force_of_infection = contact_rate * y[INFECTIOUS];
+
+        let infected = self.transitions(y[SUSCEPTIBLE], force_of_infection, tau);
+        let breakthrough = self.transitions(
+            y[VACCINATED],
+            (1.0 - params.vaccine_efficacy) * force_of_infection,
+            tau,
+        );
+        let vaccinated = self.transitions(y[SUSCEPTIBLE] - infected, params.vaccination_rate, tau);
+        let waned = self.transitions(y[REMOVED], params.waning_rate, tau);
+
+        let (variant_infected, variant_breakthrough, reinfected) = match &params.variant {
+            Some(variant) => {
+                let force_of_infection =
+                    variant.transmissibility * contact_rate * y[INFECTIOUS_VARIANT];
+                let efficacy = params.vaccine_efficacy * (1.0 - variant.immunity_escape);
+                (
+                    self.transitions(
+                        y[SUSCEPTIBLE] - infected - vaccinated,
+                        force_of_infection + variant.importation_rate,
+                        tau,
+                    ),
+                    self.transitions(
+                        y[VACCINATED] - breakthrough,
+                        (1.0 - efficacy) * force_of_infection,
+                        tau,
+                    ),
+                    self.transitions(
+                        y[REMOVED] - waned,
+                        variant.immunity_escape * force_of_infection,
+                        tau,
+                    ),
+                )
+            }
+            None => (0.0, 0.0, 0.0),
+        };
+
+        let infectious = self.transitions(y[EXPOSED], params.infection_rate, tau);
+        let variant_infectious = self.transitions(y[EXPOSED_VARIANT], params.infection_rate, tau);
+        let recovered = self.transitions(y[INFECTIOUS], params.recovery_rate, tau);
+        let variant_recovered = self.transitions(y[INFECTIOUS_VARIANT], params.recovery_rate, tau);
+        let leaving_infectious = recovered + variant_recovered;
+        let hospitalized = self.draw(leaving_infectious, params.hospitalization_rate);
+        let deaths = self.draw(leaving_infectious - hospitalized, params.fatality_rate);
+
+        let leaving_hospital = self.transitions(y[HOSPITALIZED], params.hospital_stay_rate, tau);
+        let hospital_overflow =
+            overflow(y[HOSPITALIZED], params.hospital_capacity * self.population);
+        let hospital_deaths = self.draw(
+            leaving_hospital,
+            hospital_overflow * params.overflow_fatality_rate,
+        );
+        let to_icu = self.draw(leaving_hospital - hospital_deaths, params.icu_rate);
+
+        let leaving_icu = self.transitions(y[ICU], params.icu_stay_rate, tau);
+        let icu_overflow = overflow(y[ICU], params.icu_capacity * self.population);
+        let icu_deaths = self.draw(
+            leaving_icu,
+            params.icu_fatality_rate * (1.0 - icu_overflow)
+                + params.overflow_fatality_rate * icu_overflow,
+        );
+
+        y[SUSCEPTIBLE] += waned - infected - vaccinated - variant_infected;
+        y[VACCINATED] += vaccinated - breakthrough - variant_breakthrough;
+        y[EXPOSED] += infected + breakthrough - infectious;
+        y[INFECTIOUS] += infectious - recovered;
+        y[EXPOSED_VARIANT] +=
+            variant_infected + variant_breakthrough + reinfected - variant_infectious;
+        y[INFECTIOUS_VARIANT] += variant_infectious - variant_recovered;
+        y[HOSPITALIZED] += hospitalized - leaving_hospital;
+        y[ICU] += to_icu - leaving_icu;
+        y[REMOVED] += (leaving_infectious - hospitalized - deaths)
+            + (leaving_hospital - hospital_deaths - to_icu)
+            + (leaving_icu - icu_deaths)
+            - waned
+            - reinfected;
+        y[DEATHS] += deaths + hospital_deaths + icu_deaths;
+        // The reproduction number isn't a count, it relaxes towards the ideal one exactly
+        y[REPRODUCTION_NUMBER] += (1.0 - (-params.compliance_factor * tau).exp())
+            * (params.ideal_reproduction_number - y[REPRODUCTION_NUMBER]);
+    }
+}
+
+impl Backend for TauLeaping {
+    fn simulate(
+        &mut self,
+        simulator: Simulator,
+        start_time: Time,
+        end_time: Time,
+    ) -> Result<Vec<State>, SimulationError> {
+        let tau = 1.0 / self.steps_per_day as f64;
+        let days = (end_time - start_time).floor().max(0.0) as usize;
+
+        let mut y = simulator.initial_state();
+        for (index, value) in y.iter_mut().enumerate() {
+            if index != REPRODUCTION_NUMBER {
+                // Seed fractions tuned for the ODE are often less than a person, keep them alive
+                let count = (*value * self.population).round();
+                *value = if *value > 0.0 { count.max(1.0) } else { count };
+            }
+        }
+        // Rounding must not add people, take any surplus out of S
+        let total: f64 = y
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| index != REPRODUCTION_NUMBER)
+            .map(|(_, value)| value)
+            .sum();
+        if total > self.population {
+            y[SUSCEPTIBLE] = (y[SUSCEPTIBLE] - (total - self.population)).max(0.0);
+        }
+
+        let mut trajectory = Vec::with_capacity(days + 1);
+        for day in 0..=days {
+            if day > 0 {
+                let day_start = start_time + (day - 1) as f64;
+                for step in 0..self.steps_per_day {
+                    self.step(&simulator, day_start + step as f64 * tau, &mut y, tau);
+                }
+            }
+            let mut state = y;
+            for (index, value) in state.iter_mut().enumerate() {
+                if index != REPRODUCTION_NUMBER {
+                    *value /= self.population;
+                }
+            }
+            trajectory.push(state);
+        }
+        Ok(trajectory)
+    }
+}
@@ -0,0 +1,345 @@
+//! Times at which a compartment crosses a threshold, e.g. infectious above 10% of the
+//! population or the reproduction number below 1, for the win and loss conditions and news
+//! that trigger partway through a level.
+//!
+//! Deterministic runs locate crossings by root finding on the integrator and can stop at them,
+//! other trajectories by interpolating linearly between days.
+use crate::config::check_range;
+use crate::{ConfigError, SimulationError, State, Time, STATE_SIZE};
+use serde::{Deserialize, Serialize};
+
+/// Crossings are located to within this many days
+const TIME_TOLERANCE: Time = 1.0e-6;
+
+/// Iterations of root finding after which the best estimate so far is kept
+const MAX_ITERATIONS: u32 = 100;
+
+/// Which way a compartment has to go through its threshold to count as crossing it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Rising,
+    Falling,
+    #[default]
+    Either,
+}
+
+/// A level watched for a compartment of the state to go through
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Threshold {
+    /// Index of the compartment in a [`State`], e.g. [`crate::INFECTIOUS`]
+    pub compartment: usize,
+    pub level: f64,
+    #[serde(default)]
+    pub direction: Direction,
+    /// Whether a deterministic run ends at the first crossing
+    #[serde(default)]
+    pub stop: bool,
+}
+
+impl Threshold {
+    pub fn new(compartment: usize, level: f64, direction: Direction) -> Self {
+        Self {
+            compartment,
+            level,
+            direction,
+            stop: false,
+        }
+    }
+
+    /// Makes deterministic runs end at the first crossing
+    pub fn stopping(mut self) -> Self {
+        self.stop = true;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check_range(
+            "compartment",
+            self.compartment as f64,
+            0.0,
+            (STATE_SIZE - 1) as f64,
+        )?;
+        check_range("level", self.level, 0.0, f64::MAX)
+    }
+
+    /// How far above the threshold the compartment is in `state`
+    fn distance(&self, state: &State) -> f64 {
+        state[self.compartment] - self.level
+    }
+
+    /// Whether the compartment goes through the threshold in its direction from `before` to
+    /// `after`. Reaching the threshold counts, leaving it after having reached it doesn't.
+    fn crossed(&self, before: &State, after: &State) -> bool {
+        let (before, after) = (self.distance(before), self.distance(after));
+        let rising = before < 0.0 && after >= 0.0;
+        let falling = before > 0.0 && after <= 0.0;
+        match self.direction {
+            Direction::Rising => rising,
+            Direction::Falling => falling,
+            Direction::Either => rising || falling,
+        }
+    }
+}
+
+/// A threshold being crossed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Crossing {
+    /// Index of the threshold in those watched
+    pub threshold: usize,
+    pub time: Time,
+    pub state: State,
+}
+
+/// Trajectory of a run watching thresholds, with the crossings found in time order. When the
+/// run stopped at a crossing, the trajectory ends on the last whole day before it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Run {
+    pub trajectory: Vec<State>,
+    pub crossings: Vec<Crossing>,
+}
+
+impl Run {
+    /// The crossing the run stopped at, if any
+    pub fn stopped_at<'a>(&'a self, thresholds: &[Threshold]) -> Option<&'a Crossing> {
+        self.crossings
+            .last()
+            .filter(|crossing| thresholds[crossing.threshold].stop)
+    }
+}
+
+/// Crossings of a trajectory holding one state per day from `start_time`, e.g. a stochastic
+/// one, located by interpolating linearly between days. Stopping thresholds are reported like
+/// the others.
+pub fn crossings(
+    trajectory: &[State],
+    start_time: Time,
+    thresholds: &[Threshold],
+) -> Vec<Crossing> {
+    let mut crossings = Vec::new();
+    for (day, pair) in trajectory.windows(2).enumerate() {
+        let (before, after) = (&pair[0], &pair[1]);
+        let from = start_time + day as Time;
+        for (index, threshold) in crossed(thresholds, before, after) {
+            let (distance_before, distance_after) =
+                (threshold.distance(before), threshold.distance(after));
+            let share = distance_before / (distance_before - distance_after);
+            crossings.push(Crossing {
+                threshold: index,
+                time: from + share,
+                state: before + (after - before) * share,
+            });
+        }
+    }
+    sort(&mut crossings);
+    crossings
+}
+
+/// Thresholds crossed between two states, with their index
+pub(crate) fn crossed<'a>(
+    thresholds: &'a [Threshold],
+    before: &'a State,
+    after: &'a State,
+) -> impl Iterator<Item = (usize, &'a Threshold)> {
+    thresholds
+        .iter()
+        .enumerate()
+        .filter(move |(_, threshold)| threshold.crossed(before, after))
+}
+
+pub(crate) fn sort(crossings: &mut [Crossing]) {
+    crossings.sort_by(|a, b| a.time.total_cmp(&b.time));
+}
+
+/// Time in `from..=to` at which the compartment of `threshold` reaches it, and the state then,
+/// knowing that it goes through it between `before` at `from` and `after` at `to`. `state_at`
+/// integrates from a time and state to a later time in between, it is given the start of the
+/// bracket so that every iteration only integrates over what is left of it.
+///
+/// Uses the Illinois variant of regula falsi, which converges superlinearly without needing
+/// the derivative of the compartment.
+pub(crate) fn locate(
+    threshold: &Threshold,
+    (from, before): (Time, &State),
+    (to, after): (Time, &State),
+    mut state_at: impl FnMut((Time, &State), Time) -> Result<State, SimulationError>,
+) -> Result<(Time, State), SimulationError> {
+    let (mut low, mut high) = (from, to);
+    let mut low_state = *before;
+    let (mut distance_low, mut distance_high) =
+        (threshold.distance(before), threshold.distance(after));
+    // The end of the bracket past the threshold, so that the state returned has reached it
+    let mut crossing = (to, *after);
+    // Which end moved last, the weight of the one that stays put is halved
+    let mut moved_low = None;
+    for _ in 0..MAX_ITERATIONS {
+        if high - low <= TIME_TOLERANCE {
+            break;
+        }
+        let time = (low * distance_high - high * distance_low) / (distance_high - distance_low);
+        // Rounding could land on an end of the bracket
+        let time = time
+            .max(low + TIME_TOLERANCE / 2.0)
+            .min(high - TIME_TOLERANCE / 2.0);
+        let state = state_at((low, &low_state), time)?;
+        let distance = threshold.distance(&state);
+        if distance == 0.0 {
+            return Ok((time, state));
+        }
+        // The start of the bracket is never on the threshold, unlike its end
+        if distance.signum() == distance_low.signum() {
+            low = time;
+            low_state = state;
+            distance_low = distance;
+            if moved_low == Some(true) {
+                distance_high /= 2.0;
+            }
+            moved_low = Some(true);
+        } else {
+            high = time;
+            distance_high = distance;
+            crossing = (time, state);
+            if moved_low == Some(false) {
+                distance_low /= 2.0;
+            }
+            moved_low = Some(false);
+        }
+    }
+    Ok(crossing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Deterministic, Simulator, SimulatorConfig, Solver, INFECTIOUS, REPRODUCTION_NUMBER,
+    };
+
+    const COMPLIANCE_FACTOR: f64 = 0.1;
+
+    /// R moves from `current` towards `ideal` as `ideal + (current - ideal) e^(-c t)`
+    fn config(current: f64, ideal: f64) -> SimulatorConfig {
+        SimulatorConfig::builder()
+            .susceptible(0.99)
+            .exposed(0.0)
+            .infectious(0.01)
+            .removed(0.0)
+            .current_reproduction_number(current)
+            .ideal_reproduction_number(ideal)
+            .compliance_factor(COMPLIANCE_FACTOR)
+            .recovery_rate(0.1)
+            .infection_rate(0.2)
+            .build()
+            .unwrap()
+    }
+
+    /// Day on which R reaches `level` on its way from `current` to `ideal`
+    fn analytic_time(current: f64, ideal: f64, level: f64) -> Time {
+        ((current - ideal) / (level - ideal)).ln() / COMPLIANCE_FACTOR
+    }
+
+    fn run(config: SimulatorConfig, thresholds: Vec<Threshold>, days: Time) -> Run {
+        let simulator = Simulator::new(config)
+            .unwrap()
+            .with_thresholds(thresholds)
+            .unwrap();
+        Deterministic::default().run(simulator, 0.0, days).unwrap()
+    }
+
+    #[test]
+    fn rising_crossing_matches_the_analytic_time() {
+        let threshold = Threshold::new(REPRODUCTION_NUMBER, 1.0, Direction::Rising);
+        let run = run(config(0.5, 3.0), vec![threshold], 50.0);
+        assert_eq!(run.crossings.len(), 1);
+        let crossing = &run.crossings[0];
+        assert!((crossing.time - analytic_time(0.5, 3.0, 1.0)).abs() < 1.0e-5);
+        assert!((crossing.state[REPRODUCTION_NUMBER] - 1.0).abs() < 1.0e-6);
+        assert!(crossing.state[REPRODUCTION_NUMBER] >= 1.0);
+    }
+
+    #[test]
+    fn falling_crossing_matches_the_analytic_time() {
+        let threshold = Threshold::new(REPRODUCTION_NUMBER, 1.0, Direction::Falling);
+        let run = run(config(3.0, 0.5), vec![threshold], 50.0);
+        assert_eq!(run.crossings.len(), 1);
+        let crossing = &run.crossings[0];
+        assert!((crossing.time - analytic_time(3.0, 0.5, 1.0)).abs() < 1.0e-5);
+        assert!(crossing.state[REPRODUCTION_NUMBER] <= 1.0);
+    }
+
+    #[test]
+    fn crossings_the_other_way_are_ignored() {
+        let rising = Threshold::new(REPRODUCTION_NUMBER, 1.0, Direction::Rising);
+        assert!(run(config(3.0, 0.5), vec![rising], 50.0)
+            .crossings
+            .is_empty());
+        let falling = Threshold::new(REPRODUCTION_NUMBER, 1.0, Direction::Falling);
+        assert!(run(config(0.5, 3.0), vec![falling], 50.0)
+            .crossings
+            .is_empty());
+    }
+
+    #[test]
+    fn either_direction_matches_a_fine_grid() {
+        let config = config(2.5, 2.5);
+        let threshold = Threshold::new(INFECTIOUS, 0.05, Direction::Either);
+        let run = run(config.clone(), vec![threshold.clone()], 200.0);
+
+        // Crossings of the infectious curve sampled every hundredth of a day
+        let simulator = Simulator::new(config).unwrap();
+        let solver = Solver::default();
+        let step = 0.01;
+        let mut reference = Vec::new();
+        let mut state = simulator.initial_state();
+        for i in 0..20_000 {
+            let time = i as Time * step;
+            let next = solver
+                .state_at(&simulator, time, time + step, state)
+                .unwrap();
+            if threshold.crossed(&state, &next) {
+                let (before, after) = (threshold.distance(&state), threshold.distance(&next));
+                reference.push(time + step * before / (before - after));
+            }
+            state = next;
+        }
+
+        // The outbreak goes above the threshold and back below it
+        assert_eq!(reference.len(), 2);
+        assert_eq!(run.crossings.len(), 2);
+        for (crossing, expected) in run.crossings.iter().zip(&reference) {
+            assert!((crossing.time - expected).abs() < 1.0e-4);
+        }
+    }
+
+    #[test]
+    fn stopping_threshold_ends_the_run() {
+        let stop = Threshold::new(REPRODUCTION_NUMBER, 1.0, Direction::Falling).stopping();
+        let later = Threshold::new(REPRODUCTION_NUMBER, 0.8, Direction::Falling);
+        let thresholds = vec![later, stop];
+        let run = run(config(3.0, 0.5), thresholds.clone(), 50.0);
+        let time = analytic_time(3.0, 0.5, 1.0);
+
+        // Only the crossing stopped at, the later one is never reached
+        assert_eq!(run.crossings.len(), 1);
+        let crossing = run.stopped_at(&thresholds).unwrap();
+        assert_eq!(crossing.threshold, 1);
+        assert!((crossing.time - time).abs() < 1.0e-5);
+        // Days 0 to the last whole one before the crossing
+        assert_eq!(run.trajectory.len(), time.floor() as usize + 1);
+    }
+
+    #[test]
+    fn interpolated_crossings_are_in_time_order() {
+        let trajectory = run(config(2.5, 2.5), Vec::new(), 200.0).trajectory;
+        let thresholds = vec![
+            Threshold::new(INFECTIOUS, 0.05, Direction::Falling),
+            Threshold::new(INFECTIOUS, 0.05, Direction::Rising),
+        ];
+        let crossings = crossings(&trajectory, 0.0, &thresholds);
+        let order: Vec<usize> = crossings
+            .iter()
+            .map(|crossing| crossing.threshold)
+            .collect();
+        assert_eq!(order, vec![1, 0]);
+    }
+}